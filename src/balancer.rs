@@ -0,0 +1,248 @@
+use crate::config::{Config, Strategy, FAILOVER_TIMEOUT, LATENCY_EWMA_WEIGHT, LATENCY_SAMPLES};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Decides which upstreams a query goes to, and in what order.
+pub struct Balancer {
+    strategy: Strategy,
+    /// `None` queries every candidate at once.
+    fanout: Option<usize>,
+    weights: Vec<u32>,
+    next: AtomicUsize,
+    hedge_delay: Duration,
+    hedge_percentile: Option<f64>,
+    /// Smoothed round-trip time per upstream, `None` until first measured.
+    latencies: Mutex<Vec<Option<Duration>>>,
+    /// Recent round-trip times per upstream, for hedging on a percentile.
+    samples: Mutex<Vec<VecDeque<Duration>>>,
+}
+
+impl Balancer {
+    pub fn new(config: &Config) -> Self {
+        let servers = &config.servers;
+        // Racing queries every upstream at once; the other strategies one at a time.
+        let fanout = match config.strategy {
+            Strategy::Race => config.fanout,
+            _ => Some(config.fanout.unwrap_or(1)),
+        };
+
+        Self {
+            strategy: config.strategy,
+            fanout: fanout.map(|fanout| fanout.max(1)),
+            weights: servers.iter().map(|server| server.weight).collect(),
+            next: AtomicUsize::new(0),
+            hedge_delay: Duration::from_millis(config.hedge_delay),
+            hedge_percentile: config.hedge_percentile,
+            latencies: Mutex::new(vec![None; servers.len()]),
+            samples: Mutex::new(vec![VecDeque::new(); servers.len()]),
+        }
+    }
+
+    /// Batches of the candidate upstreams to query in turn, and how long to
+    /// wait for an answer from one batch before starting the next.
+    pub fn plan(&self, candidates: &[usize]) -> (Vec<Vec<usize>>, Duration) {
+        let order = self.order(candidates);
+        let fanout = self.fanout.unwrap_or(order.len()).max(1);
+        let mut batches: Vec<Vec<usize>> =
+            order.chunks(fanout).map(|batch| batch.to_vec()).collect();
+
+        match self.strategy {
+            Strategy::Hedged => {
+                // Hedging fans out to everything that is left in one go.
+                if batches.len() > 2 {
+                    let rest = batches.split_off(1).concat();
+                    batches.push(rest);
+                }
+                let delay = order
+                    .first()
+                    .map(|&index| self.hedge_delay(index))
+                    .unwrap_or(self.hedge_delay);
+                (batches, delay)
+            }
+            _ => (batches, Duration::from_secs(FAILOVER_TIMEOUT)),
+        }
+    }
+
+    /// The configured percentile of the upstream's recent round-trip times,
+    /// or the fixed hedge delay until there are enough samples.
+    fn hedge_delay(&self, index: usize) -> Duration {
+        let Some(percentile) = self.hedge_percentile else {
+            return self.hedge_delay;
+        };
+        let mut samples: Vec<Duration> = match self.samples.lock() {
+            Ok(samples) => samples[index].iter().copied().collect(),
+            Err(_) => Vec::new(),
+        };
+        if samples.len() < LATENCY_SAMPLES / 4 {
+            return self.hedge_delay;
+        }
+
+        samples.sort();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (samples.len() - 1) as f64).round();
+        samples[rank as usize]
+    }
+
+    /// The candidate upstream indices in the order they should be tried.
+    pub fn order(&self, candidates: &[usize]) -> Vec<usize> {
+        let count = candidates.len();
+        match self.strategy {
+            Strategy::Race | Strategy::Failover => candidates.to_vec(),
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count)
+                    .map(|i| candidates[(start + i) % count])
+                    .collect()
+            }
+            Strategy::Weighted => self.weighted_order(candidates),
+            Strategy::LowestLatency | Strategy::Hedged => {
+                let latencies = match self.latencies.lock() {
+                    Ok(latencies) => latencies.clone(),
+                    Err(_) => vec![None; self.weights.len()],
+                };
+                let mut order = candidates.to_vec();
+                // Unmeasured upstreams go first so every upstream gets measured.
+                order.sort_by_key(|&i| latencies[i].unwrap_or(Duration::ZERO));
+                order
+            }
+        }
+    }
+
+    /// Draw upstreams one by one with probability proportional to their weight.
+    fn weighted_order(&self, candidates: &[usize]) -> Vec<usize> {
+        let mut remaining = candidates.to_vec();
+        let mut order = Vec::with_capacity(remaining.len());
+
+        while !remaining.is_empty() {
+            let total: u64 = remaining.iter().map(|&i| self.weights[i] as u64).sum();
+            let position = if total == 0 {
+                0
+            } else {
+                let mut pick = random_u64() % total;
+                remaining
+                    .iter()
+                    .position(|&i| {
+                        let weight = self.weights[i] as u64;
+                        if pick < weight {
+                            true
+                        } else {
+                            pick -= weight;
+                            false
+                        }
+                    })
+                    .unwrap_or(0)
+            };
+            order.push(remaining.remove(position));
+        }
+        order
+    }
+
+    /// Fold an observed round-trip time into the upstream's moving average.
+    /// Failures are recorded with the time spent waiting, which pushes slow
+    /// or broken upstreams back in the lowest-latency order.
+    /// A query cancelled after `elapsed` took at least that long, so this
+    /// only ever raises the upstream's estimate.
+    pub fn record_unfinished(&self, index: usize, elapsed: Duration) {
+        let latency = match self.latencies.lock() {
+            Ok(latencies) => latencies.get(index).copied().flatten(),
+            Err(_) => return,
+        };
+        if elapsed > latency.unwrap_or(Duration::ZERO) {
+            self.record_latency(index, elapsed);
+        }
+    }
+
+    pub fn record_latency(&self, index: usize, rtt: Duration) {
+        if let Ok(mut samples) = self.samples.lock() {
+            if let Some(samples) = samples.get_mut(index) {
+                if samples.len() == LATENCY_SAMPLES {
+                    samples.pop_front();
+                }
+                samples.push_back(rtt);
+            }
+        }
+
+        if let Ok(mut latencies) = self.latencies.lock() {
+            if let Some(latency) = latencies.get_mut(index) {
+                *latency = Some(match *latency {
+                    Some(previous) => {
+                        previous.mul_f64(1.0 - LATENCY_EWMA_WEIGHT)
+                            + rtt.mul_f64(LATENCY_EWMA_WEIGHT)
+                    }
+                    None => rtt,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(settings: &str, weights: &[u32]) -> Balancer {
+        let mut config = settings.to_string();
+        for (i, weight) in weights.iter().enumerate() {
+            config.push_str(&format!(
+                "\n[[servers]]\naddress = \"192.0.2.{}\"\nweight = {}\ndescription = \"test\"\n",
+                i + 1,
+                weight
+            ));
+        }
+        Balancer::new(&toml::from_str(&config).unwrap())
+    }
+
+    const ALL: &[usize] = &[0, 1, 2];
+
+    #[test]
+    fn test_strategies() {
+        let race = balancer("", &[1, 1, 1]);
+        assert_eq!(race.plan(ALL).0, vec![vec![0, 1, 2]]);
+        assert_eq!(race.plan(&[1, 2]).0, vec![vec![1, 2]]);
+
+        let round_robin = balancer("strategy = \"round-robin\"", &[1, 1, 1]);
+        assert_eq!(round_robin.plan(ALL).0, vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(round_robin.order(ALL), vec![1, 2, 0]);
+
+        // A zero weight is only ever drawn once every other upstream is used up.
+        let weighted = balancer("strategy = \"weighted\"\nfanout = 2", &[0, 5, 0]);
+        let (batches, _) = weighted.plan(ALL);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0][0], 1);
+
+        let fastest = balancer("strategy = \"lowest-latency\"", &[1, 1, 1]);
+        fastest.record_latency(0, Duration::from_millis(80));
+        fastest.record_latency(1, Duration::from_millis(20));
+        assert_eq!(fastest.order(ALL), vec![2, 1, 0]);
+        fastest.record_latency(2, Duration::from_millis(50));
+        assert_eq!(fastest.order(ALL), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_hedged_plan() {
+        let hedged = balancer("strategy = \"hedged\"\nhedge_delay = 40", &[1, 1, 1, 1]);
+        hedged.record_latency(2, Duration::from_millis(5));
+        for index in [0, 1, 3] {
+            hedged.record_latency(index, Duration::from_millis(30));
+        }
+        let (batches, delay) = hedged.plan(&[0, 1, 2, 3]);
+        assert_eq!(batches, vec![vec![2], vec![0, 1, 3]]);
+        assert_eq!(delay, Duration::from_millis(40));
+
+        let percentile = balancer("strategy = \"hedged\"\nhedge_percentile = 90.0", &[1, 1]);
+        for ms in 1..=20 {
+            percentile.record_latency(0, Duration::from_millis(ms));
+            percentile.record_latency(1, Duration::from_millis(ms * 10));
+        }
+        let (batches, delay) = percentile.plan(&[0, 1]);
+        assert_eq!(batches, vec![vec![0], vec![1]]);
+        assert_eq!(delay, Duration::from_millis(18));
+    }
+}
@@ -1,27 +1,508 @@
+use crate::forward::Router;
 use anyhow::{Ok, Result};
+use hickory_proto::rr::RecordType;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Config {
+    /// Addresses to listen on, e.g. `0.0.0.0:53`, `[::]:53` or `*:53`.
+    /// Defaults to IPv4 and IPv6 loopback on the `--port` given on the command line.
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// EDNS(0) UDP payload size advertised to upstreams and clients.
+    #[serde(default = "default_edns_buffer_size")]
+    pub edns_buffer_size: u16,
+    /// Resolvers used to look up hostname upstreams, e.g. `["9.9.9.9", "2620:fe::fe"]`.
+    #[serde(default)]
+    pub bootstrap: Vec<String>,
+    /// How queries are spread over the upstreams.
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Upstreams queried at once. Defaults to all for `race` and 1 otherwise.
+    #[serde(default)]
+    pub fanout: Option<usize>,
+    /// Milliseconds the `hedged` strategy waits before querying more upstreams.
+    #[serde(default = "default_hedge_delay")]
+    pub hedge_delay: u64,
+    /// Wait for this percentile (e.g. 95) of the fastest upstream's recent
+    /// round-trip times instead of the fixed `hedge_delay`.
+    #[serde(default)]
+    pub hedge_percentile: Option<f64>,
+    /// Milliseconds an answer waits for one from a higher-`priority` upstream.
+    #[serde(default = "default_priority_window")]
+    pub priority_window: u64,
+    /// When an authoritative NXDOMAIN or NODATA answer is forwarded.
+    #[serde(default)]
+    pub negative_answers: NegativePolicy,
+    /// Health checks and circuit breaking for upstreams.
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// Conditional forwarding of domains to upstream groups.
+    #[serde(default)]
+    pub forward: Vec<ForwardRule>,
+    /// Domains whose answers must be confirmed by several upstreams.
+    #[serde(default)]
+    pub consensus: Vec<ConsensusRule>,
+    /// Internal domains whose other record types are answered locally.
+    /// Defaults to A records only for `cluster.local`.
+    #[serde(default = "default_internal_domains")]
+    pub internal_domains: Vec<InternalDomain>,
+    /// Group of upstreams for names that match no forwarding rule.
+    #[serde(default = "default_group")]
+    pub default_group: String,
+    /// Default privacy policy for upstreams that do not set their own.
+    #[serde(default)]
+    pub privacy: Privacy,
     pub servers: Vec<ServerConfig>,
+    /// Optional DNS over TLS listener for clients.
+    #[serde(default)]
+    pub tls: Option<TlsListenerConfig>,
+    /// Optional DNS over HTTPS listener for clients.
+    #[serde(default)]
+    pub https: Option<HttpsListenerConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TlsListenerConfig {
+    #[serde(default = "default_tls_listen")]
+    pub listen: Vec<String>,
+    /// PEM certificate chain, reloaded when the file changes.
+    pub cert: PathBuf,
+    /// PEM private key, reloaded when the file changes.
+    pub key: PathBuf,
+}
+
+/// Sends names under any of `domains` to the upstreams in `group`. The rule
+/// with the longest matching suffix wins.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ForwardRule {
+    pub domains: Vec<String>,
+    pub group: String,
+}
+
+/// Answers for names under any of `domains` are only forwarded once
+/// `required` upstreams returned the same records. The rule with the longest
+/// matching suffix wins.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ConsensusRule {
+    pub domains: Vec<String>,
+    pub required: usize,
+    /// Differing answers tolerated before the query fails with SERVFAIL.
+    #[serde(default)]
+    pub tolerance: usize,
+}
+
+/// Only the `allow`ed record types for names under any of `domains` are
+/// forwarded, and the rest get a synthetic `response`, e.g. to spare
+/// Kubernetes DNS the AAAA lookups it cannot answer. The rule with the
+/// longest matching suffix wins.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct InternalDomain {
+    pub domains: Vec<String>,
+    /// Record types passed to the upstreams, e.g. `["A", "AAAA", "SRV"]`.
+    #[serde(default = "default_internal_allow")]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub response: SyntheticResponse,
+}
+
+/// The answer for record types an internal domain does not forward.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyntheticResponse {
+    /// NOERROR without answers, with a synthetic SOA for the domain.
+    #[default]
+    NoData,
+    /// NXDOMAIN with a synthetic SOA for the domain.
+    NxDomain,
+    /// REFUSED.
+    Refused,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthConfig {
+    /// Seconds between probes of every upstream, 0 to rely on real traffic only.
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    #[serde(default = "default_probe_name")]
+    pub probe_name: String,
+    #[serde(default = "default_probe_type")]
+    pub probe_type: String,
+    /// Consecutive failures after which an upstream's circuit opens.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Seconds an open circuit skips the upstream before a trial query.
+    #[serde(default = "default_open_duration")]
+    pub open_duration: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_health_interval(),
+            probe_name: default_probe_name(),
+            probe_type: default_probe_type(),
+            failure_threshold: default_failure_threshold(),
+            open_duration: default_open_duration(),
+        }
+    }
+}
+
+fn default_health_interval() -> u64 {
+    HEALTH_CHECK_INTERVAL
+}
+
+fn default_probe_name() -> String {
+    ".".to_string()
+}
+
+fn default_probe_type() -> String {
+    "NS".to_string()
+}
+
+fn default_failure_threshold() -> u32 {
+    CIRCUIT_FAILURE_THRESHOLD
+}
+
+fn default_open_duration() -> u64 {
+    CIRCUIT_OPEN_DURATION
+}
+
+/// Transport used to reach an upstream.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Dot,
+    Doh,
+    Doq,
+}
+
+impl Protocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Protocol::Udp | Protocol::Tcp => DNS_PORT,
+            Protocol::Dot | Protocol::Doq => DOT_PORT,
+            Protocol::Doh => DOH_PORT,
+        }
+    }
+}
+
+/// How queries are spread over the upstreams. Upstreams are tried in the
+/// strategy's order, `fanout` at a time, moving on to the next batch when a
+/// batch fails or does not answer within `FAILOVER_TIMEOUT`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Query all upstreams at once and take the first answer.
+    #[default]
+    Race,
+    /// Always in configuration order.
+    Failover,
+    /// Start with the next upstream on every query.
+    RoundRobin,
+    /// Random order, weighted by each upstream's `weight`.
+    Weighted,
+    /// Fastest first, by a moving average of observed round-trip times.
+    LowestLatency,
+    /// The fastest upstreams first, and all others once `hedge_delay` or
+    /// `hedge_percentile` passes without an answer.
+    Hedged,
+}
+
+/// When a negative answer (NXDOMAIN, or NOERROR without answers, with the
+/// zone's SOA in the authority section) from an upstream is forwarded. A
+/// positive answer from any upstream always wins over negative ones.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NegativePolicy {
+    /// Forward the first negative answer right away.
+    First,
+    /// Forward a negative answer once every upstream queried so far has replied.
+    #[default]
+    All,
+    /// Forward a negative answer once a majority of the candidate upstreams agree.
+    Quorum,
+}
+
+/// Whether queries may leave the host unencrypted.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Privacy {
+    /// Never send a query in cleartext: no fallback from DoT, and cleartext upstreams are skipped.
+    Strict,
+    /// Fall back from DoT to cleartext only when the encrypted transport fails.
+    #[default]
+    Opportunistic,
+    /// Also retry in cleartext when DoT answers without data.
+    Cleartext,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ServerConfig {
     pub address: String,
+    /// One of `udp`, `tcp`, `dot`, `doh` or `doq`. When unset the protocol
+    /// follows `url`, `use_quic` and `use_tls` as in earlier versions.
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+    /// Defaults to the standard port for the protocol.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Name to verify the upstream certificate against and send as SNI. Defaults to `address`.
+    #[serde(default)]
+    pub tls_name: Option<String>,
+    /// PEM bundle of additional CA certificates trusted for this upstream.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Base64 SHA-256 digests of the upstream's SubjectPublicKeyInfo (RFC 7858 section 4.2).
+    /// The certificate must match one of them in addition to passing verification.
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+    /// PEM client certificate chain presented to upstreams that require mutual TLS.
+    /// Reloaded when the file changes.
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key for `client_cert`.
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// Trust the built-in public root store as well as `ca_file`. With neither, only the pins are checked.
+    #[serde(default = "default_system_roots")]
+    pub system_roots: bool,
+    /// Resolvers used to look up `address` when it is a hostname. Overrides the global `bootstrap`.
+    #[serde(default)]
+    pub bootstrap: Vec<String>,
+    /// Upstream group used by forwarding rules. Defaults to `default`.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Relative share of queries under the `weighted` strategy.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Answers from higher-priority upstreams beat earlier ones from lower
+    /// priorities that arrive within `priority_window`.
+    #[serde(default)]
+    pub priority: u32,
+    /// Overrides the global `privacy` policy for this upstream.
+    #[serde(default)]
+    pub privacy: Option<Privacy>,
+    #[serde(default)]
     pub use_tls: bool,
+    /// DNS over HTTPS endpoint, e.g. `https://1.1.1.1/dns-query`. Takes precedence over `use_tls`.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Use DNS over QUIC on port 853. Takes precedence over `use_tls`.
+    #[serde(default)]
+    pub use_quic: bool,
     pub description: String,
 }
 
+impl ServerConfig {
+    pub fn protocol(&self) -> Protocol {
+        match self.protocol {
+            Some(protocol) => protocol,
+            None if self.url.is_some() => Protocol::Doh,
+            None if self.use_quic => Protocol::Doq,
+            None if self.use_tls => Protocol::Dot,
+            None => Protocol::Udp,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(self.protocol().default_port())
+    }
+
+    pub fn group(&self) -> &str {
+        self.group.as_deref().unwrap_or(DEFAULT_GROUP)
+    }
+
+    pub fn privacy(&self) -> Privacy {
+        self.privacy.unwrap_or_default()
+    }
+
+    pub fn tls_name(&self) -> &str {
+        self.tls_name.as_deref().unwrap_or(&self.address)
+    }
+
+    /// `address:port`, with IPv6 addresses in brackets.
+    pub fn host_port(&self) -> String {
+        if self.address.contains(':') {
+            format!("[{}]:{}", self.address, self.port())
+        } else {
+            format!("{}:{}", self.address, self.port())
+        }
+    }
+
+    /// DNS over HTTPS URL, built from the address and port when `url` is not set.
+    pub fn url(&self) -> String {
+        match &self.url {
+            Some(url) => url.clone(),
+            None => format!("https://{}/dns-query", self.host_port()),
+        }
+    }
+
+    /// Identifies the upstream for connection reuse.
+    pub fn endpoint(&self) -> String {
+        match self.protocol() {
+            Protocol::Doh => self.url(),
+            protocol => format!("{:?}://{}", protocol, self.host_port()).to_lowercase(),
+        }
+    }
+
+    /// Whether this upstream is reached over a TLS-based protocol.
+    pub fn uses_tls(&self) -> bool {
+        matches!(
+            self.protocol(),
+            Protocol::Dot | Protocol::Doh | Protocol::Doq
+        )
+    }
+
+    /// The same upstream over cleartext UDP on port 53, used when DoT fails.
+    pub fn cleartext(&self) -> ServerConfig {
+        ServerConfig {
+            protocol: Some(Protocol::Udp),
+            port: None,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HttpsListenerConfig {
+    #[serde(default = "default_https_listen")]
+    pub listen: Vec<String>,
+    /// PEM certificate chain, reloaded when the file changes.
+    pub cert: PathBuf,
+    /// PEM private key, reloaded when the file changes.
+    pub key: PathBuf,
+}
+
+fn default_https_listen() -> Vec<String> {
+    vec!["*:443".to_string()]
+}
+
+fn default_tls_listen() -> Vec<String> {
+    vec!["*:853".to_string()]
+}
+
+fn default_group() -> String {
+    DEFAULT_GROUP.to_string()
+}
+
+fn default_internal_domains() -> Vec<InternalDomain> {
+    vec![InternalDomain {
+        domains: vec![DEFAULT_INTERNAL_DOMAIN.to_string()],
+        allow: default_internal_allow(),
+        response: SyntheticResponse::default(),
+    }]
+}
+
+fn default_internal_allow() -> Vec<String> {
+    vec!["A".to_string()]
+}
+
+fn default_hedge_delay() -> u64 {
+    DEFAULT_HEDGE_DELAY
+}
+
+fn default_priority_window() -> u64 {
+    DEFAULT_PRIORITY_WINDOW
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_system_roots() -> bool {
+    true
+}
+
+fn default_edns_buffer_size() -> u16 {
+    DEFAULT_EDNS_BUFFER_SIZE
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            edns_buffer_size: DEFAULT_EDNS_BUFFER_SIZE,
+            bootstrap: Vec::new(),
+            strategy: Strategy::default(),
+            fanout: None,
+            hedge_delay: DEFAULT_HEDGE_DELAY,
+            hedge_percentile: None,
+            priority_window: DEFAULT_PRIORITY_WINDOW,
+            negative_answers: NegativePolicy::default(),
+            health: HealthConfig::default(),
+            forward: Vec::new(),
+            consensus: Vec::new(),
+            internal_domains: default_internal_domains(),
+            default_group: default_group(),
+            privacy: Privacy::default(),
+            servers: Vec::new(),
+            tls: None,
+            https: None,
+        }
+    }
+}
+
 impl Config {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
         let contents = fs::read_to_string(path.as_ref())?;
 
         match path.as_ref().extension().and_then(|ext| ext.to_str()) {
             Some("toml") => {
-                let config: Config = toml::from_str(&contents)?;
+                let mut config: Config = toml::from_str(&contents)?;
+                for server in config.servers.iter_mut() {
+                    server.privacy.get_or_insert(config.privacy);
+                    if server.bootstrap.is_empty() {
+                        server.bootstrap = config.bootstrap.clone();
+                    }
+                }
+                for rule in &config.forward {
+                    if !config
+                        .servers
+                        .iter()
+                        .any(|server| server.group() == rule.group)
+                    {
+                        return Err(anyhow::anyhow!(
+                            "Forwarding rule for {:?} names group {} which has no servers",
+                            rule.domains,
+                            rule.group
+                        ));
+                    }
+                }
+                for rule in &config.consensus {
+                    if rule.required == 0 {
+                        return Err(anyhow::anyhow!(
+                            "Consensus rule for {:?} requires no upstreams",
+                            rule.domains
+                        ));
+                    }
+                }
+                if let Some((domain, required, available)) =
+                    Router::new(&config).unsatisfiable_consensus()
+                {
+                    return Err(anyhow::anyhow!(
+                        "Consensus for {} requires {} upstreams but its group has {}",
+                        domain,
+                        required,
+                        available
+                    ));
+                }
+                for rule in &config.internal_domains {
+                    for record_type in &rule.allow {
+                        if RecordType::from_str(&record_type.to_ascii_uppercase()).is_err() {
+                            return Err(anyhow::anyhow!(
+                                "Internal domains {:?} allow unknown record type {}",
+                                rule.domains,
+                                record_type
+                            ));
+                        }
+                    }
+                }
                 Ok(config)
             }
 
@@ -34,4 +515,28 @@ impl Config {
 //pub const LOCALHOST_PORT_V6: &str = "[::1]:5353";
 pub const CACHE_TTL: u64 = 300; // 5 minutes
 pub const DNS_TIMEOUT: u64 = 3; // seconds
-pub const KUBERNETES_DOMAIN: &str = "cluster.local.";
+pub const DEFAULT_EDNS_BUFFER_SIZE: u16 = 1232; // bytes, the DNS flag day 2020 recommendation
+pub const UDP_RECV_BUFFER_SIZE: usize = 4096; // bytes, largest client query we accept over UDP
+pub const FAILOVER_TIMEOUT: u64 = 1; // seconds before the next batch of upstreams is tried
+pub const DEFAULT_HEDGE_DELAY: u64 = 100; // milliseconds before a hedged query fans out
+pub const DEFAULT_PRIORITY_WINDOW: u64 = 200; // milliseconds an answer waits for a higher-priority upstream
+pub const LATENCY_SAMPLES: usize = 64; // recent round-trip times kept per upstream for percentiles
+pub const LATENCY_EWMA_WEIGHT: f64 = 0.3; // weight of the newest round-trip time in the moving average
+pub const HEALTH_CHECK_INTERVAL: u64 = 15; // seconds between upstream probes
+pub const CIRCUIT_FAILURE_THRESHOLD: u32 = 3; // consecutive failures before a circuit opens
+pub const CIRCUIT_OPEN_DURATION: u64 = 30; // seconds an open circuit skips its upstream
+pub const STREAM_IDLE_TIMEOUT: u64 = 30; // seconds, unless the upstream advertises edns-tcp-keepalive
+pub const STREAM_MAX_PENDING: usize = 64; // outstanding queries per upstream stream connection
+pub const STREAM_POOL_SIZE: usize = 4; // stream connections per upstream
+pub const DNS_PORT: u16 = 53;
+pub const DOT_PORT: u16 = 853;
+pub const DOH_PORT: u16 = 443;
+pub const BOOTSTRAP_MIN_TTL: u64 = 30; // seconds, shortest time bootstrapped upstream addresses are kept
+pub const BOOTSTRAP_RETRY_DELAY: u64 = 5; // seconds stale bootstrapped addresses are reused before the next lookup
+pub const HAPPY_EYEBALLS_DELAY: u64 = 250; // milliseconds, RFC 8305 connection attempt delay
+pub const CERT_RELOAD_INTERVAL: u64 = 30; // seconds between checks for rotated certificates
+pub const DEFAULT_GROUP: &str = "default";
+pub const DEFAULT_INTERNAL_DOMAIN: &str = "cluster.local"; // answered locally unless internal_domains is set
+pub const INTERNAL_SOA_TTL: u32 = 60; // seconds, TTL and negative TTL of synthetic SOA records
+pub const TCP_IDLE_TIMEOUT: u64 = 10; // seconds, RFC 7766 recommends at least a few seconds
+pub const TCP_PIPELINE_LIMIT: usize = 32; // outstanding queries per TCP connection
@@ -0,0 +1,385 @@
+use crate::config::{
+    BOOTSTRAP_MIN_TTL, BOOTSTRAP_RETRY_DELAY, CACHE_TTL, DNS_PORT, DNS_TIMEOUT,
+    HAPPY_EYEBALLS_DELAY,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hickory_proto::op::{Message, Query};
+use hickory_proto::rr::{Name, RData, RecordType};
+use hickory_proto::serialize::binary::BinDecodable;
+use lazy_static::lazy_static;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+
+lazy_static! {
+    static ref RESOLVED: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Addresses for an upstream host. IP literals are used as is. Hostnames are
+/// looked up through the `bootstrap` resolvers, so the system resolver (which
+/// may well be this forwarder) is not involved, and cached until their TTL
+/// runs out. Without bootstrap resolvers the system resolver is used.
+pub async fn resolve(host: &str, port: u16, bootstrap: &[String]) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    if bootstrap.is_empty() {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        return non_empty(host, interleave(addrs));
+    }
+
+    // Not held during the lookup, so other upstreams resolve meanwhile.
+    let stale = match RESOLVED.lock().await.get(host) {
+        Some((ips, expires)) if Instant::now() < *expires => {
+            return non_empty(host, to_socket_addrs(ips, port));
+        }
+        Some((ips, _)) => Some(ips.clone()),
+        None => None,
+    };
+
+    match lookup(host, bootstrap).await {
+        Ok((ips, ttl)) => {
+            let addrs = to_socket_addrs(&ips, port);
+            RESOLVED
+                .lock()
+                .await
+                .insert(host.to_string(), (ips, Instant::now() + ttl));
+            non_empty(host, addrs)
+        }
+        Err(e) => match stale {
+            // Better a stale address than none while the bootstrap resolvers
+            // are down. It is kept a little longer so queries meanwhile do not
+            // each wait for the failing lookup.
+            Some(ips) => {
+                eprintln!(
+                    "Bootstrap refresh of {} failed ({}), using stale addresses",
+                    host, e
+                );
+                let addrs = to_socket_addrs(&ips, port);
+                let retry = Instant::now() + Duration::from_secs(BOOTSTRAP_RETRY_DELAY);
+                RESOLVED.lock().await.insert(host.to_string(), (ips, retry));
+                non_empty(host, addrs)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+fn non_empty(host: &str, addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Failed to resolve DNS server {}", host),
+        ));
+    }
+    Ok(addrs)
+}
+
+fn to_socket_addrs(ips: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    interleave(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+}
+
+/// Order addresses IPv6 first, alternating between families (RFC 8305 section 4).
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    v6.reverse();
+    v4.reverse();
+
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop());
+        ordered.extend(v4.pop());
+    }
+    ordered
+}
+
+fn bootstrap_addr(resolver: &str) -> io::Result<SocketAddr> {
+    resolver
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            resolver
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        })
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Bootstrap resolver must be an IP address: {}", resolver),
+            )
+        })
+}
+
+/// Look up A and AAAA records, trying each bootstrap resolver in turn.
+async fn lookup(host: &str, bootstrap: &[String]) -> io::Result<(Vec<IpAddr>, Duration)> {
+    let name = Name::from_str(&format!("{}.", host.trim_end_matches('.')))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut last_error = None;
+
+    for resolver in bootstrap {
+        let addr = bootstrap_addr(resolver)?;
+        let (v6, v4) = tokio::join!(
+            query(addr, &name, RecordType::AAAA),
+            query(addr, &name, RecordType::A)
+        );
+
+        let mut ips = Vec::new();
+        let mut ttl = CACHE_TTL;
+        for result in [v6, v4] {
+            match result {
+                Ok((found, found_ttl)) => {
+                    if !found.is_empty() {
+                        ttl = ttl.min(found_ttl);
+                    }
+                    ips.extend(found);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        if !ips.is_empty() {
+            println!("Bootstrapped {} via {}: {:?}", host, resolver, ips);
+            return Ok((ips, Duration::from_secs(ttl.max(BOOTSTRAP_MIN_TTL))));
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No bootstrap resolver knows {}", host),
+        )
+    }))
+}
+
+async fn query(
+    resolver: SocketAddr,
+    name: &Name,
+    record_type: RecordType,
+) -> io::Result<(Vec<IpAddr>, u64)> {
+    let id = RandomState::new().build_hasher().finish() as u16;
+    let mut message = Message::new();
+    message.set_id(id);
+    message.set_recursion_desired(true);
+    message.add_query(Query::query(name.clone(), record_type));
+    let query_data = message.to_vec().map_err(std::io::Error::other)?;
+
+    let bind_addr = if resolver.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(resolver).await?;
+    socket.send(&query_data).await?;
+
+    let mut response_buf = vec![0; 4096];
+    let size = tokio::time::timeout(
+        Duration::from_secs(DNS_TIMEOUT),
+        socket.recv(&mut response_buf),
+    )
+    .await
+    .map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, "Bootstrap query timed out")
+    })??;
+
+    let response = Message::from_bytes(&response_buf[..size]).map_err(std::io::Error::other)?;
+    if response.id() != id {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Bootstrap response ID mismatch",
+        ));
+    }
+
+    let mut ttl = CACHE_TTL;
+    let ips = response
+        .answers()
+        .iter()
+        .filter_map(|record| {
+            let ip = match record.data() {
+                RData::A(a) => IpAddr::V4(a.0),
+                RData::AAAA(aaaa) => IpAddr::V6(aaaa.0),
+                _ => return None,
+            };
+            ttl = ttl.min(record.ttl() as u64);
+            Some(ip)
+        })
+        .collect();
+    Ok((ips, ttl))
+}
+
+/// Happy Eyeballs (RFC 8305): start `connect` with the first address and
+/// begin the next attempt whenever one fails or the previous has not
+/// connected within the attempt delay. The first to connect wins.
+pub async fn happy_eyeballs<F, Fut, T>(addrs: &[SocketAddr], connect: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut remaining = addrs.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = remaining.next() {
+            attempts.push(connect(*addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "No address to connect to")
+            }));
+        }
+
+        tokio::select! {
+            result = attempts.next() => match result {
+                Some(Ok(connection)) => return Ok(connection),
+                Some(Err(e)) => last_error = Some(e),
+                None => {}
+            },
+            _ = tokio::time::sleep(Duration::from_millis(HAPPY_EYEBALLS_DELAY)),
+                if remaining.len() > 0 => {}
+        }
+    }
+}
+
+pub async fn connect_tcp(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    happy_eyeballs(addrs, TcpStream::connect).await
+}
+
+/// Send a datagram to the first address that takes it. There is no
+/// handshake to race, but an address family the host cannot route fails
+/// right away on connect or send, and the next address is tried.
+pub async fn send_udp(addrs: &[SocketAddr], data: &[u8]) -> io::Result<UdpSocket> {
+    let mut last_error = None;
+    for addr in addrs {
+        let bind_addr = if addr.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let sent = async {
+            let socket = UdpSocket::bind(bind_addr).await?;
+            socket.connect(addr).await?;
+            socket.send(data).await?;
+            Ok::<_, io::Error>(socket)
+        };
+        match sent.await {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "No address to send to")
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::closed_port;
+    use hickory_proto::op::MessageType;
+    use hickory_proto::rr::rdata::{A, AAAA};
+    use hickory_proto::rr::Record;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// Answers every A and AAAA query for one name with a fixed address.
+    async fn spawn_bootstrap_resolver() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let mut response = Message::from_bytes(&buf[..size]).unwrap();
+                response.set_message_type(MessageType::Response);
+                let query = response.queries()[0].clone();
+                let rdata = match query.query_type() {
+                    RecordType::A => RData::A(A(Ipv4Addr::new(192, 0, 2, 10))),
+                    _ => RData::AAAA(AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 10))),
+                };
+                response.add_answer(Record::from_rdata(query.name().clone(), 60, rdata));
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_resolve_through_bootstrap() {
+        let resolver = spawn_bootstrap_resolver().await;
+        let addrs = resolve("dns.bootstrap.test", 853, &[resolver.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "[2001:db8::a]:853".parse().unwrap(),
+                "192.0.2.10:853".parse().unwrap()
+            ]
+        );
+
+        let (_, expires) = RESOLVED.lock().await["dns.bootstrap.test"];
+        assert!(expires > Instant::now() + Duration::from_secs(BOOTSTRAP_MIN_TTL));
+
+        // IP literals never need a lookup.
+        let addrs = resolve("[2001:db8::1]", 53, &[]).await.unwrap();
+        assert_eq!(addrs, vec!["[2001:db8::1]:53".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_stale_addresses_survive_failed_refresh() {
+        // The refresh fails, nothing listens on the resolver's port.
+        let resolver = SocketAddr::from(([127, 0, 0, 1], closed_port()));
+
+        let stale = vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20))];
+        RESOLVED
+            .lock()
+            .await
+            .insert("dns.stale.test".to_string(), (stale, Instant::now()));
+
+        let addrs = resolve("dns.stale.test", 853, &[resolver.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["192.0.2.20:853".parse().unwrap()]);
+
+        let (_, expires) = RESOLVED.lock().await["dns.stale.test"];
+        assert!(expires > Instant::now());
+        assert!(expires <= Instant::now() + Duration::from_secs(BOOTSTRAP_RETRY_DELAY));
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_skips_unreachable_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        let bad = SocketAddr::from(([127, 0, 0, 1], closed_port()));
+
+        let stream = connect_tcp(&[bad, good]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+    }
+
+    #[tokio::test]
+    async fn test_udp_falls_back_to_next_address() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let good = receiver.local_addr().unwrap();
+        // Broadcasts are refused without SO_BROADCAST, much like an address
+        // family the host has no route for.
+        let bad: SocketAddr = "255.255.255.255:53".parse().unwrap();
+
+        let socket = send_udp(&[bad, good], b"query").await.unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), good);
+        let mut buf = [0u8; 16];
+        let size = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"query");
+    }
+}
@@ -22,13 +22,21 @@ impl DnsCache {
         }
     }
 
-    fn create_cache_key(query: &[u8]) -> Option<Vec<u8>> {
+    pub fn create_cache_key(query: &[u8]) -> Option<Vec<u8>> {
         if let Ok(message) = Message::from_bytes(query) {
             // Only use the query name and type as the cache key
             let mut key = Vec::new();
             for question in message.queries() {
                 key.extend_from_slice(question.name().to_ascii().as_bytes());
-                key.extend_from_slice(&question.query_type().to_string().as_bytes());
+                key.extend_from_slice(question.query_type().to_string().as_bytes());
+            }
+            // DNSSEC-aware clients get answers with signatures, keep them apart
+            if message
+                .extensions()
+                .as_ref()
+                .is_some_and(|edns| edns.flags().dnssec_ok)
+            {
+                key.extend_from_slice(b"+DO");
             }
             Some(key)
         } else {
@@ -0,0 +1,94 @@
+use crate::dns::cache::DnsCache;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Delivers the leader's response, if it got one, to every waiter.
+type Waiters = broadcast::Sender<Option<Vec<u8>>>;
+
+/// Single-flight de-duplication of identical queries, keyed like the cache.
+/// The first query for a key is resolved upstream, and queries arriving
+/// while it is in flight wait for its response instead of resolving again.
+pub struct Coalescer {
+    in_flight: Mutex<HashMap<Vec<u8>, Waiters>>,
+}
+
+/// The leader's claim on a key. Dropping it unfinished, when the leader is
+/// cancelled, closes the channel so that waiters resolve on their own.
+struct Flight<'a> {
+    coalescer: &'a Coalescer,
+    key: Option<Vec<u8>>,
+}
+
+impl Flight<'_> {
+    fn sender(&mut self) -> Option<Waiters> {
+        let key = self.key.take()?;
+        self.coalescer.in_flight.lock().ok()?.remove(&key)
+    }
+
+    /// Hand the response to every waiter and release the key.
+    fn finish(mut self, response: &Option<Vec<u8>>) {
+        if let Some(sender) = self.sender() {
+            let _ = sender.send(response.clone());
+        }
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        self.sender();
+    }
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve `query` with `resolve`, unless an identical query is already
+    /// in flight, in which case its response is shared, rewritten to this
+    /// query's message ID.
+    pub async fn resolve<F, Fut>(&self, query: &[u8], resolve: F) -> Option<Vec<u8>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        let Some(key) = DnsCache::create_cache_key(query) else {
+            return resolve().await;
+        };
+
+        let waiting = {
+            let Ok(mut in_flight) = self.in_flight.lock() else {
+                return resolve().await;
+            };
+            match in_flight.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    in_flight.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = waiting {
+            return match receiver.recv().await {
+                Ok(response) => {
+                    response.and_then(|response| DnsCache::update_dns_id(query, response))
+                }
+                // The leader was cancelled before it had a response
+                Err(_) => resolve().await,
+            };
+        }
+
+        let flight = Flight {
+            coalescer: self,
+            key: Some(key),
+        };
+        let response = resolve().await;
+        flight.finish(&response);
+        response
+    }
+}
@@ -0,0 +1,217 @@
+use hickory_proto::op::{Edns, Message};
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::serialize::binary::BinDecodable;
+use std::time::Duration;
+
+/// Extended DNS Error info codes (RFC 8914 section 4).
+pub const EDE_OTHER: u16 = 0;
+pub const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+pub const EDE_NETWORK_ERROR: u16 = 23;
+const EDNS_EXTENDED_ERROR: u16 = 15;
+
+/// Largest reply a client accepts over UDP: its advertised EDNS payload size,
+/// or 512 bytes when it did not send an OPT record (RFC 6891 section 6.2.5).
+pub fn client_payload_size(request: &Message) -> usize {
+    request.max_payload() as usize
+}
+
+/// Advertise our own buffer size to the upstream while keeping the client's
+/// EDNS options and DO bit. Clients without EDNS get an OPT record added.
+pub fn prepare_upstream_query(query: &mut Message, buffer_size: u16) {
+    let edns = query.extensions_mut().get_or_insert_with(Edns::new);
+    edns.set_max_payload(buffer_size);
+    // edns-tcp-keepalive is hop-by-hop and must not be relayed (RFC 7828 section 3.2)
+    edns.options_mut().remove(EdnsCode::Keepalive);
+}
+
+/// Signal to a stream upstream that we want to keep the connection open
+/// and learn its idle timeout (RFC 7828 section 3.2.1).
+pub fn request_tcp_keepalive(query: &mut Message) {
+    query
+        .extensions_mut()
+        .get_or_insert_with(Edns::new)
+        .options_mut()
+        .insert(EdnsOption::Unknown(
+            u16::from(EdnsCode::Keepalive),
+            Vec::new(),
+        ));
+}
+
+/// The idle timeout an upstream advertised in its edns-tcp-keepalive option.
+/// The value is sent in units of 100 milliseconds.
+pub fn tcp_keepalive_timeout(response: &Message) -> Option<Duration> {
+    match response
+        .extensions()
+        .as_ref()?
+        .option(EdnsCode::Keepalive)?
+    {
+        EdnsOption::Unknown(_, data) if data.len() == 2 => {
+            let timeout = u16::from_be_bytes([data[0], data[1]]);
+            Some(Duration::from_millis(u64::from(timeout) * 100))
+        }
+        _ => None,
+    }
+}
+
+/// Attach an Extended DNS Error (RFC 8914) with a human-readable explanation.
+pub fn add_extended_error(edns: &mut Edns, info_code: u16, text: &str) {
+    let mut data = info_code.to_be_bytes().to_vec();
+    data.extend_from_slice(text.as_bytes());
+    edns.options_mut()
+        .insert(EdnsOption::Unknown(EDNS_EXTENDED_ERROR, data));
+}
+
+/// Shape an upstream (or synthesized) response for the client that asked.
+/// Responses only carry an OPT record if the request did, and that record
+/// advertises our buffer size rather than the upstream's.
+pub fn adapt_response(request: &Message, response: Vec<u8>, buffer_size: u16) -> Vec<u8> {
+    let Ok(mut message) = Message::from_bytes(&response) else {
+        return response;
+    };
+
+    match request.extensions() {
+        Some(request_edns) => {
+            let dnssec_ok = request_edns.flags().dnssec_ok;
+            let edns = message.extensions_mut().get_or_insert_with(Edns::new);
+            edns.set_max_payload(buffer_size);
+            edns.options_mut().remove(EdnsCode::Keepalive);
+            // Never claim DNSSEC records to a client that did not ask for them.
+            if !dnssec_ok {
+                edns.set_dnssec_ok(false);
+            }
+        }
+        None => {
+            *message.extensions_mut() = None;
+        }
+    }
+
+    message.to_vec().unwrap_or(response)
+}
+
+/// Replace a UDP reply that exceeds the client's payload size with an empty,
+/// truncated one so the client retries over TCP (RFC 7766 section 5).
+pub fn truncate_for_udp(request: &[u8], response: Vec<u8>) -> Vec<u8> {
+    let Ok(request) = Message::from_bytes(request) else {
+        return response;
+    };
+
+    if response.len() <= client_payload_size(&request) {
+        return response;
+    }
+
+    match Message::from_bytes(&response).and_then(|message| message.truncate().to_vec()) {
+        Ok(truncated) => truncated,
+        Err(_) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{MessageType, Query};
+    use hickory_proto::rr::rdata::TXT;
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use std::str::FromStr;
+
+    fn create_request(edns: Option<u16>, dnssec_ok: bool) -> Message {
+        let mut message = Message::new();
+        message.set_id(4242);
+        let name = Name::from_str("example.com.").unwrap();
+        message.add_query(Query::query(name, RecordType::TXT));
+        if let Some(payload) = edns {
+            let mut edns = Edns::new();
+            edns.set_max_payload(payload);
+            edns.set_dnssec_ok(dnssec_ok);
+            message.set_edns(edns);
+        }
+        message
+    }
+
+    fn create_response(request: &Message, records: usize) -> Vec<u8> {
+        let mut response = request.clone();
+        response.set_message_type(MessageType::Response);
+        let name = Name::from_str("example.com.").unwrap();
+        for i in 0..records {
+            let txt = TXT::new(vec![format!("record-{i:04}-{}", "x".repeat(40))]);
+            response.add_answer(Record::from_rdata(name.clone(), 300, RData::TXT(txt)));
+        }
+        let mut edns = Edns::new();
+        edns.set_max_payload(4096);
+        edns.set_dnssec_ok(true);
+        response.set_edns(edns);
+        response.to_vec().unwrap()
+    }
+
+    #[test]
+    fn test_prepare_upstream_query_preserves_do_bit() {
+        let mut query = create_request(Some(512), true);
+        prepare_upstream_query(&mut query, 1232);
+        let edns = query.extensions().as_ref().unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(edns.flags().dnssec_ok);
+
+        let mut query = create_request(None, false);
+        prepare_upstream_query(&mut query, 1232);
+        assert_eq!(query.max_payload(), 1232);
+    }
+
+    #[test]
+    fn test_adapt_response_strips_opt_for_plain_clients() {
+        let request = create_request(None, false);
+        let response = adapt_response(&request, create_response(&request, 1), 1232);
+        assert!(Message::from_bytes(&response)
+            .unwrap()
+            .extensions()
+            .is_none());
+
+        let request = create_request(Some(1232), false);
+        let response = adapt_response(&request, create_response(&request, 1), 1232);
+        let message = Message::from_bytes(&response).unwrap();
+        let edns = message.extensions().as_ref().unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(!edns.flags().dnssec_ok);
+    }
+
+    #[test]
+    fn test_tcp_keepalive_option() {
+        let mut query = create_request(Some(1232), false);
+        request_tcp_keepalive(&mut query);
+        let query = Message::from_bytes(&query.to_vec().unwrap()).unwrap();
+        assert!(query
+            .extensions()
+            .as_ref()
+            .unwrap()
+            .option(EdnsCode::Keepalive)
+            .is_some());
+
+        let mut response = create_request(None, false);
+        let mut edns = Edns::new();
+        edns.options_mut().insert(EdnsOption::Unknown(
+            u16::from(EdnsCode::Keepalive),
+            150u16.to_be_bytes().to_vec(),
+        ));
+        response.set_edns(edns);
+        let response = Message::from_bytes(&response.to_vec().unwrap()).unwrap();
+        assert_eq!(
+            tcp_keepalive_timeout(&response),
+            Some(Duration::from_secs(15))
+        );
+    }
+
+    #[test]
+    fn test_truncate_for_udp() {
+        let request = create_request(None, false);
+        let response = create_response(&request, 20);
+        assert!(response.len() > 512);
+
+        let truncated = truncate_for_udp(&request.to_vec().unwrap(), response.clone());
+        let message = Message::from_bytes(&truncated).unwrap();
+        assert!(message.truncated());
+        assert!(message.answers().is_empty());
+        assert_eq!(message.queries().len(), 1);
+
+        let request = create_request(Some(4096), false);
+        let fits = truncate_for_udp(&request.to_vec().unwrap(), response.clone());
+        assert_eq!(fits, response);
+    }
+}
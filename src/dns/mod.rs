@@ -1,2 +1,6 @@
+pub mod bootstrap;
 pub mod cache;
+pub mod coalesce;
+pub mod edns;
+pub mod pool;
 pub mod query;
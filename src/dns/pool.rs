@@ -0,0 +1,331 @@
+use crate::config::{STREAM_IDLE_TIMEOUT, STREAM_MAX_PENDING, STREAM_POOL_SIZE};
+use crate::dns::edns;
+use hickory_proto::op::Message;
+use hickory_proto::serialize::binary::BinDecodable;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+type Pending = Arc<Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>>;
+
+/// A long-lived, length-framed DNS stream (RFC 7766) carrying many
+/// outstanding queries at once. Each query is sent with a message ID that is
+/// unique on this connection and the client's ID is restored on the answer.
+struct Connection {
+    writer: mpsc::Sender<Vec<u8>>,
+    pending: Pending,
+    next_id: AtomicU16,
+    closed: Arc<AtomicBool>,
+}
+
+/// Removes the pending entry if the caller gives up before the answer arrives.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+impl Connection {
+    fn spawn<S>(stream: S) -> Arc<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(STREAM_MAX_PENDING);
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        // Updated from the upstream's edns-tcp-keepalive option, in milliseconds.
+        let idle_timeout = Arc::new(AtomicU64::new(STREAM_IDLE_TIMEOUT * 1000));
+
+        let writer_pending = Arc::clone(&pending);
+        let writer_closed = Arc::clone(&closed);
+        let writer_idle_timeout = Arc::clone(&idle_timeout);
+        tokio::spawn(async move {
+            loop {
+                let idle = Duration::from_millis(writer_idle_timeout.load(Ordering::Relaxed));
+                tokio::select! {
+                    query = rx.recv() => {
+                        let Some(query) = query else { break };
+                        let length = (query.len() as u16).to_be_bytes();
+                        if writer.write_all(&length).await.is_err()
+                            || writer.write_all(&query).await.is_err()
+                        {
+                            break;
+                        }
+                    }
+                    _ = tokio::time::sleep(idle) => {
+                        let is_idle = writer_pending.lock().map(|p| p.is_empty()).unwrap_or(true);
+                        if is_idle {
+                            break;
+                        }
+                    }
+                }
+            }
+            // No new queries go to this connection; the reader drains what is left.
+            writer_closed.store(true, Ordering::Relaxed);
+            let _ = writer.shutdown().await;
+        });
+
+        let reader_pending = Arc::clone(&pending);
+        let reader_closed = Arc::clone(&closed);
+        tokio::spawn(async move {
+            loop {
+                let mut length_buf = [0u8; 2];
+                if reader.read_exact(&mut length_buf).await.is_err() {
+                    break;
+                }
+                let mut response = vec![0; u16::from_be_bytes(length_buf) as usize];
+                if reader.read_exact(&mut response).await.is_err() || response.len() < 2 {
+                    break;
+                }
+
+                if let Ok(message) = Message::from_bytes(&response) {
+                    if let Some(timeout) = edns::tcp_keepalive_timeout(&message) {
+                        idle_timeout.store(timeout.as_millis() as u64, Ordering::Relaxed);
+                    }
+                }
+
+                let id = u16::from_be_bytes([response[0], response[1]]);
+                let waiter = reader_pending.lock().ok().and_then(|mut p| p.remove(&id));
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(response);
+                }
+            }
+            // Dropping the senders wakes every waiter with an error.
+            reader_closed.store(true, Ordering::Relaxed);
+            if let Ok(mut pending) = reader_pending.lock() {
+                pending.clear();
+            }
+        });
+
+        Arc::new(Self {
+            writer: tx,
+            pending,
+            next_id: AtomicU16::new(0),
+            closed,
+        })
+    }
+
+    fn is_usable(&self) -> bool {
+        !self.closed.load(Ordering::Relaxed) && self.load() < STREAM_MAX_PENDING
+    }
+
+    fn load(&self) -> usize {
+        self.pending.lock().map(|p| p.len()).unwrap_or(usize::MAX)
+    }
+
+    async fn query(&self, mut query_data: Vec<u8>) -> io::Result<Vec<u8>> {
+        if query_data.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Query too short",
+            ));
+        }
+        let original_id = [query_data[0], query_data[1]];
+        let (tx, rx) = oneshot::channel();
+
+        let id = {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|_| io::Error::other("Pending queries lock poisoned"))?;
+            let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&id) {
+                id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.insert(id, tx);
+            id
+        };
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            id,
+        };
+
+        query_data[..2].copy_from_slice(&id.to_be_bytes());
+        self.writer
+            .send(query_data)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        let mut response = rx
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed"))?;
+        response[..2].copy_from_slice(&original_id);
+        Ok(response)
+    }
+}
+
+/// One async lock per upstream, so a slow handshake with one upstream does
+/// not stall queries to the others.
+pub struct KeyedMutex<T> {
+    entries: Mutex<HashMap<String, Arc<tokio::sync::Mutex<T>>>>,
+}
+
+impl<T: Default> KeyedMutex<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn lock(&self, key: &str) -> io::Result<tokio::sync::OwnedMutexGuard<T>> {
+        let entry = self
+            .entries
+            .lock()
+            .map_err(|_| io::Error::other("Connection lock poisoned"))?
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        Ok(entry.lock_owned().await)
+    }
+}
+
+/// Long-lived stream connections per upstream, shared by all requests.
+pub struct StreamPool {
+    upstreams: KeyedMutex<Vec<Arc<Connection>>>,
+}
+
+impl StreamPool {
+    pub fn new() -> Self {
+        Self {
+            upstreams: KeyedMutex::new(),
+        }
+    }
+
+    /// Pick the least loaded live connection to `key`, opening a new one with
+    /// `connect` only when every pooled connection is closed or saturated.
+    async fn connection<F, Fut, S>(&self, key: &str, connect: &F) -> io::Result<Arc<Connection>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let mut pool = self.upstreams.lock(key).await?;
+        pool.retain(|connection| !connection.closed.load(Ordering::Relaxed));
+
+        let best = pool
+            .iter()
+            .filter(|connection| connection.is_usable())
+            .min_by_key(|connection| connection.load())
+            .cloned();
+
+        match best {
+            Some(connection) => Ok(connection),
+            None if pool.len() >= STREAM_POOL_SIZE => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "All pooled connections are busy",
+            )),
+            None => {
+                let connection = Connection::spawn(connect().await?);
+                pool.push(Arc::clone(&connection));
+                Ok(connection)
+            }
+        }
+    }
+
+    /// Send a query over a pooled connection. If the connection turns out to
+    /// be dead (the upstream closed it while idle), retry once on a new one.
+    pub async fn query<F, Fut, S>(
+        &self,
+        key: &str,
+        query_data: Vec<u8>,
+        connect: F,
+    ) -> io::Result<Vec<u8>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let connection = self.connection(key, &connect).await?;
+        match connection.query(query_data.clone()).await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                println!("Pooled connection to {} failed ({}), reconnecting", key, e);
+                connection.closed.store(true, Ordering::Relaxed);
+                let connection = self.connection(key, &connect).await?;
+                connection.query(query_data).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{MessageType, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+
+    fn create_query(id: u16, name: &str) -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(id);
+        message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        message.to_vec().unwrap()
+    }
+
+    /// Answers two queries in reverse order to prove responses are matched by ID.
+    async fn reverse_responder(stream: tokio::io::DuplexStream) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut queries = Vec::new();
+        for _ in 0..2 {
+            let mut length_buf = [0u8; 2];
+            reader.read_exact(&mut length_buf).await.unwrap();
+            let mut query = vec![0; u16::from_be_bytes(length_buf) as usize];
+            reader.read_exact(&mut query).await.unwrap();
+            queries.push(query);
+        }
+        for query in queries.into_iter().rev() {
+            let mut response = Message::from_bytes(&query).unwrap();
+            response.set_message_type(MessageType::Response);
+            let response = response.to_vec().unwrap();
+            writer
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            writer.write_all(&response).await.unwrap();
+        }
+        // Keep the connection open so the pool can reuse it.
+        std::future::pending::<()>().await;
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_queries_share_one_connection() {
+        let pool = StreamPool::new();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connect = || {
+            let connects = Arc::clone(&connects);
+            async move {
+                connects.fetch_add(1, Ordering::Relaxed);
+                let (client, server) = tokio::io::duplex(4096);
+                tokio::spawn(reverse_responder(server));
+                Ok(client)
+            }
+        };
+
+        // Both clients use the same message ID; the pool must keep them apart.
+        let (first, second) = tokio::join!(
+            pool.query("upstream", create_query(7, "one.example."), connect),
+            pool.query("upstream", create_query(7, "two.example."), connect),
+        );
+
+        let first = Message::from_bytes(&first.unwrap()).unwrap();
+        let second = Message::from_bytes(&second.unwrap()).unwrap();
+        assert_eq!(first.id(), 7);
+        assert_eq!(second.id(), 7);
+        assert_eq!(first.queries()[0].name().to_ascii(), "one.example.");
+        assert_eq!(second.queries()[0].name().to_ascii(), "two.example.");
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+}
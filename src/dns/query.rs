@@ -1,131 +1,524 @@
+use crate::config::{Privacy, Protocol, ServerConfig, DNS_TIMEOUT};
+use crate::dns::bootstrap;
+use crate::dns::edns;
+use crate::dns::pool::{KeyedMutex, StreamPool};
+use crate::stats::STATS;
+use crate::tls;
 use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::RecordType;
 use hickory_proto::serialize::binary::BinDecodable;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::client::conn::http2::SendRequest;
+use hyper::{header, Request, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use lazy_static::lazy_static;
+use quinn::crypto::rustls::QuicClientConfig;
 use std::collections::HashMap;
-use std::net::ToSocketAddrs;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::pki_types::ServerName;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::ClientConfig;
 use tokio_rustls::TlsConnector;
 
 lazy_static! {
     static ref TLS_CONNECTIONS: Mutex<HashMap<String, Arc<TlsConnector>>> =
         Mutex::new(HashMap::new());
+    static ref TLS_POOL: StreamPool = StreamPool::new();
+    static ref HTTPS_CONNECTIONS: KeyedMutex<Option<SendRequest<Full<Bytes>>>> = KeyedMutex::new();
+    static ref QUIC_CONNECTIONS: Mutex<HashMap<String, quinn::Connection>> =
+        Mutex::new(HashMap::new());
+    static ref QUIC_CLIENT_CONFIGS: Mutex<HashMap<String, quinn::ClientConfig>> =
+        Mutex::new(HashMap::new());
+}
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+const DOQ_ALPN: &[u8] = b"doq";
+
+fn tls_client_config(
+    server: &ServerConfig,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> io::Result<ClientConfig> {
+    tls::client_config(server, alpn_protocols).map_err(std::io::Error::other)
 }
 
-async fn get_tls_connector(dns_server: &str) -> Arc<TlsConnector> {
+async fn get_tls_connector(
+    server: &ServerConfig,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> io::Result<Arc<TlsConnector>> {
     let mut connections: tokio::sync::MutexGuard<'_, HashMap<String, Arc<TlsConnector>>> =
         TLS_CONNECTIONS.lock().await;
+    let key = server.endpoint();
 
-    if let Some(connector) = connections.get(dns_server) {
-        connector.clone()
+    if let Some(connector) = connections.get(&key) {
+        Ok(connector.clone())
     } else {
-        let mut root_cert_store = RootCertStore::empty();
-        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth();
+        // The default in-memory session store lets reconnects resume TLS sessions,
+        // so the connector is kept per upstream rather than rebuilt.
+        let config = tls_client_config(server, alpn_protocols)?;
 
         let connector = Arc::new(TlsConnector::from(Arc::new(config)));
-        connections.insert(dns_server.to_string(), connector.clone());
-        connector
+        connections.insert(key, connector.clone());
+        Ok(connector)
     }
 }
+
+async fn upstream_addrs(server: &ServerConfig) -> io::Result<Vec<SocketAddr>> {
+    bootstrap::resolve(&server.address, server.port(), &server.bootstrap).await
+}
+
+fn server_name(name: &str) -> io::Result<ServerName<'static>> {
+    ServerName::try_from(name.to_owned())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+/// Only NOERROR responses with answers and authoritative negative answers
+/// are passed on to the client.
+fn accept_response(server: &ServerConfig, response_buf: Vec<u8>) -> (String, Option<Vec<u8>>) {
+    if let Ok(message) = Message::from_bytes(&response_buf) {
+        let answered =
+            message.response_code() == ResponseCode::NoError && !message.answers().is_empty();
+        if answered || is_negative(&message) {
+            return (server.address.to_string(), Some(response_buf));
+        }
+    }
+
+    (server.address.to_string(), None)
+}
+
+/// Whether the response is an authoritative NXDOMAIN or NODATA answer,
+/// i.e. it carries the zone's SOA in the authority section (RFC 2308).
+pub fn is_negative(message: &Message) -> bool {
+    let negative = match message.response_code() {
+        ResponseCode::NXDomain => true,
+        ResponseCode::NoError => message.answers().is_empty(),
+        _ => false,
+    };
+    negative
+        && message
+            .name_servers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::SOA)
+}
+
+/// Query an upstream using the protocol configured for it.
+pub async fn query_upstream(
+    server: &ServerConfig,
+    query_data: Vec<u8>,
+) -> io::Result<(String, Option<Vec<u8>>)> {
+    if !server.uses_tls() && server.privacy() == Privacy::Strict {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "Cleartext upstream not allowed in strict privacy mode",
+        ));
+    }
+
+    match server.protocol() {
+        Protocol::Udp => query_dns(server, query_data).await,
+        Protocol::Tcp => query_dns_tcp(server, query_data).await,
+        Protocol::Dot => query_dns_with_fallback(server, query_data).await,
+        Protocol::Doh => query_dns_https(server, query_data).await,
+        Protocol::Doq => query_dns_quic(server, query_data).await,
+    }
+}
+
+async fn connect_tls(server: &ServerConfig) -> io::Result<TlsStream<TcpStream>> {
+    let addrs = upstream_addrs(server).await?;
+    let connector = get_tls_connector(server, Vec::new()).await?;
+
+    // Connect using TLS, verifying the certificate against `tls_name`
+    let stream = bootstrap::connect_tcp(&addrs).await?;
+    connector
+        .connect(server_name(server.tls_name())?, stream)
+        .await
+}
+
 pub async fn query_dns_tls(
-    dns_server: &str,
+    server: &ServerConfig,
     query_data: Vec<u8>,
 ) -> io::Result<(String, Option<Vec<u8>>)> {
-    let addr = format!("{}:853", dns_server);
-    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::NotFound, "Failed to resolve DNS server")
-    })?;
+    // Ask the upstream how long it will keep our pooled connection open
+    let query_data = match Message::from_bytes(&query_data) {
+        Ok(mut message) => {
+            edns::request_tcp_keepalive(&mut message);
+            message.to_vec().unwrap_or(query_data)
+        }
+        Err(_) => query_data,
+    };
+
+    let response_buf = TLS_POOL
+        .query(&server.endpoint(), query_data, || connect_tls(server))
+        .await?;
 
-    let connector = get_tls_connector(dns_server).await;
+    Ok(accept_response(server, response_buf))
+}
+
+async fn connect_https(server: &ServerConfig, uri: &Uri) -> io::Result<SendRequest<Full<Bytes>>> {
+    let host = uri
+        .host()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "URL has no host"))?;
+    let port = uri.port_u16().unwrap_or(server.port());
+    let addrs = bootstrap::resolve(host, port, &server.bootstrap).await?;
+
+    // RFC 8484 recommends HTTP/2, negotiated via ALPN
+    let connector = get_tls_connector(server, vec![b"h2".to_vec()]).await?;
+
+    let stream = bootstrap::connect_tcp(&addrs).await?;
+    let name = server.tls_name.as_deref().unwrap_or(host);
+    let tls_stream = connector.connect(server_name(name)?, stream).await?;
+
+    let (sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls_stream))
+            .await
+            .map_err(std::io::Error::other)?;
 
-    // Connect using TLS
-    let stream = TcpStream::connect(addr).await?;
-    let domain = ServerName::try_from(dns_server.to_owned())
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
+    let endpoint = server.endpoint();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("DoH connection to {} closed: {}", endpoint, e);
+        }
+    });
+
+    Ok(sender)
+}
 
-    let mut tls_stream = connector.connect(domain, stream).await?;
+/// Reuse the HTTP/2 connection to the upstream, which multiplexes concurrent
+/// queries, and open a new one if it has been closed.
+async fn get_https_sender(
+    server: &ServerConfig,
+    uri: &Uri,
+) -> io::Result<SendRequest<Full<Bytes>>> {
+    let mut connection = HTTPS_CONNECTIONS.lock(&server.endpoint()).await?;
+
+    if let Some(sender) = connection.as_ref() {
+        if !sender.is_closed() {
+            return Ok(sender.clone());
+        }
+    }
+
+    let sender = connect_https(server, uri).await?;
+    *connection = Some(sender.clone());
+    Ok(sender)
+}
+
+/// DNS over HTTPS (RFC 8484), POSTing the wire-format query to the upstream's URL.
+pub async fn query_dns_https(
+    server: &ServerConfig,
+    query_data: Vec<u8>,
+) -> io::Result<(String, Option<Vec<u8>>)> {
+    let uri: Uri = server
+        .url()
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if query_data.len() < 2 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Query too short",
+        ));
+    }
+
+    // Use message ID 0 so HTTP caches can share answers (RFC 8484 section 4.1)
+    let original_id = [query_data[0], query_data[1]];
+    let mut query_data = query_data;
+    query_data[..2].copy_from_slice(&[0, 0]);
+
+    let mut sender = get_https_sender(server, &uri).await?;
+    sender.ready().await.map_err(std::io::Error::other)?;
+
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+        .header(header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE)
+        .body(Full::new(Bytes::from(query_data)))
+        .map_err(std::io::Error::other)?;
+
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(std::io::Error::other)?;
+    if response.status() != StatusCode::OK {
+        return Err(std::io::Error::other(format!(
+            "DoH server {} returned {}",
+            server.url(),
+            response.status()
+        )));
+    }
+
+    let mut response_buf = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(std::io::Error::other)?
+        .to_bytes()
+        .to_vec();
+    if response_buf.len() < 2 {
+        return Ok((server.address.to_string(), None));
+    }
+    response_buf[..2].copy_from_slice(&original_id);
+
+    Ok(accept_response(server, response_buf))
+}
+
+fn quic_client_config(tls_config: ClientConfig) -> io::Result<quinn::ClientConfig> {
+    let crypto = QuicClientConfig::try_from(tls_config).map_err(std::io::Error::other)?;
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+async fn connect_quic(
+    addr: SocketAddr,
+    server_name: &str,
+    client_config: quinn::ClientConfig,
+) -> io::Result<quinn::Connection> {
+    let bind_addr: SocketAddr = if addr.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    let mut endpoint = quinn::Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(client_config);
+
+    endpoint
+        .connect(addr, server_name)
+        .map_err(std::io::Error::other)?
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Send one query on its own bidirectional stream (RFC 9250 section 4.2).
+async fn exchange_quic(connection: &quinn::Connection, query_data: &[u8]) -> io::Result<Vec<u8>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
 
-    // DNS over TLS requires a 2-byte prefix
     let length = (query_data.len() as u16).to_be_bytes();
-    tls_stream.write_all(&length).await?;
-    tls_stream.write_all(&query_data).await?;
+    send.write_all(&length).await?;
+    send.write_all(query_data).await?;
+    send.finish()?;
+
+    let mut length_buf = [0u8; 2];
+    recv.read_exact(&mut length_buf)
+        .await
+        .map_err(std::io::Error::other)?;
+    let mut response_buf = vec![0; u16::from_be_bytes(length_buf) as usize];
+    recv.read_exact(&mut response_buf)
+        .await
+        .map_err(std::io::Error::other)?;
+    Ok(response_buf)
+}
+
+async fn query_quic_connection(
+    key: &str,
+    addrs: &[SocketAddr],
+    server_name: &str,
+    client_config: quinn::ClientConfig,
+    query_data: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    if query_data.len() < 2 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Query too short",
+        ));
+    }
+
+    // DoQ requires message ID 0, the stream identifies the query
+    let original_id = [query_data[0], query_data[1]];
+    let mut query_data = query_data;
+    query_data[..2].copy_from_slice(&[0, 0]);
+
+    let cached = QUIC_CONNECTIONS
+        .lock()
+        .await
+        .get(key)
+        .filter(|connection| connection.close_reason().is_none())
+        .cloned();
+
+    let response = match &cached {
+        Some(connection) => exchange_quic(connection, &query_data).await,
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "No DoQ connection",
+        )),
+    };
+
+    let mut response_buf = match response {
+        Ok(response_buf) => response_buf,
+        Err(e) => {
+            if cached.is_some() {
+                println!("DoQ connection to {} failed ({}), reconnecting", key, e);
+            }
+            let connection = bootstrap::happy_eyeballs(addrs, |addr| {
+                connect_quic(addr, server_name, client_config.clone())
+            })
+            .await?;
+            QUIC_CONNECTIONS
+                .lock()
+                .await
+                .insert(key.to_string(), connection.clone());
+            exchange_quic(&connection, &query_data).await?
+        }
+    };
+
+    if response_buf.len() < 2 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Response too short",
+        ));
+    }
+    response_buf[..2].copy_from_slice(&original_id);
+    Ok(response_buf)
+}
+
+async fn get_quic_client_config(server: &ServerConfig) -> io::Result<quinn::ClientConfig> {
+    let mut configs = QUIC_CLIENT_CONFIGS.lock().await;
+    let key = server.endpoint();
+
+    if let Some(config) = configs.get(&key) {
+        return Ok(config.clone());
+    }
+
+    let config = quic_client_config(tls_client_config(server, vec![DOQ_ALPN.to_vec()])?)?;
+    configs.insert(key, config.clone());
+    Ok(config)
+}
+
+/// DNS over QUIC (RFC 9250). The connection is reused across queries.
+pub async fn query_dns_quic(
+    server: &ServerConfig,
+    query_data: Vec<u8>,
+) -> io::Result<(String, Option<Vec<u8>>)> {
+    let addrs = upstream_addrs(server).await?;
+
+    let client_config = get_quic_client_config(server).await?;
+    let response_buf = query_quic_connection(
+        &server.endpoint(),
+        &addrs,
+        server.tls_name(),
+        client_config,
+        query_data,
+    )
+    .await?;
+
+    Ok(accept_response(server, response_buf))
+}
+
+/// Send a query over a stream transport using the 2-byte length prefix
+/// shared by DNS over TCP and DNS over TLS, and read back one response.
+async fn exchange_framed<S>(stream: &mut S, query_data: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let length = (query_data.len() as u16).to_be_bytes();
+    stream.write_all(&length).await?;
+    stream.write_all(query_data).await?;
 
     // Read response length
     let mut length_buf = [0u8; 2];
-    tls_stream.read_exact(&mut length_buf).await?;
+    stream.read_exact(&mut length_buf).await?;
     let response_length = u16::from_be_bytes(length_buf) as usize;
 
     // Read response
     let mut response_buf = vec![0; response_length];
-    tls_stream.read_exact(&mut response_buf).await?;
+    stream.read_exact(&mut response_buf).await?;
+    Ok(response_buf)
+}
 
-    if let Ok(message) = Message::from_bytes(&response_buf) {
-        if message.response_code() == ResponseCode::NoError && !message.answers().is_empty() {
-            return Ok((dns_server.to_string(), Some(response_buf)));
-        }
-    }
+pub async fn query_dns_tcp(
+    server: &ServerConfig,
+    query_data: Vec<u8>,
+) -> io::Result<(String, Option<Vec<u8>>)> {
+    let addrs = upstream_addrs(server).await?;
 
-    Ok((dns_server.to_string(), None))
+    let mut stream = bootstrap::connect_tcp(&addrs).await?;
+    let response_buf = exchange_framed(&mut stream, &query_data).await?;
+
+    Ok(accept_response(server, response_buf))
 }
 
 pub async fn query_dns(
-    dns_server: &str,
+    server: &ServerConfig,
     query_data: Vec<u8>,
 ) -> io::Result<(String, Option<Vec<u8>>)> {
-    let addr = format!("{}:53", dns_server);
-    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::NotFound, "Failed to resolve DNS server")
-    })?;
+    let addrs = upstream_addrs(server).await?;
 
-    let upstream = UdpSocket::bind("0.0.0.0:0").await?;
-    upstream.connect(addr).await?;
+    // Expect at most the payload size we advertised in the query's OPT record.
+    // One spare byte lets us tell a full datagram from one that did not fit.
+    let buffer_size = Message::from_bytes(&query_data)
+        .map(|message| message.max_payload() as usize)
+        .unwrap_or(512);
+    let mut response_buf = vec![0; buffer_size + 1];
 
-    let mut response_buf = vec![0; 1024];
+    let upstream = bootstrap::send_udp(&addrs, &query_data).await?;
+    let size = tokio::time::timeout(
+        Duration::from_secs(DNS_TIMEOUT),
+        upstream.recv(&mut response_buf),
+    )
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "UDP query timed out"))??;
 
-    upstream.send(&query_data).await?;
-    upstream.recv(&mut response_buf).await?;
+    if size > buffer_size {
+        println!(
+            "Response from {} exceeds {} bytes, retrying over TCP",
+            server.address, buffer_size
+        );
+        return query_dns_tcp(server, query_data).await;
+    }
+    response_buf.truncate(size);
 
     if let Ok(message) = Message::from_bytes(&response_buf) {
-        if message.response_code() == ResponseCode::NoError && !message.answers().is_empty() {
-            return Ok((dns_server.to_string(), Some(response_buf)));
+        if message.truncated() {
+            println!(
+                "Truncated response from {}, retrying over TCP",
+                server.address
+            );
+            return query_dns_tcp(server, query_data).await;
         }
     }
 
-    Ok((dns_server.to_string(), None))
+    Ok(accept_response(server, response_buf))
+}
+
+fn downgrade(server: &ServerConfig, reason: &str) -> ServerConfig {
+    let count = STATS.record_downgrade();
+    println!(
+        "Downgrading {} to cleartext DNS: {} ({} downgrades so far)",
+        server.address, reason, count
+    );
+    server.cleartext()
 }
 
 pub async fn query_dns_with_fallback(
-    dns_server: &str,
+    server: &ServerConfig,
     query_data: Vec<u8>,
 ) -> io::Result<(String, Option<Vec<u8>>)> {
-    // First try DNS over TLS
-    match query_dns_tls(dns_server, query_data.clone()).await {
-        Ok((server, Some(response))) => {
+    // First try DNS over TLS; whether to fall back depends on the privacy policy
+    match query_dns_tls(server, query_data.clone()).await {
+        Ok((address, Some(response))) => {
             // DoT succeeded and returned a valid response
-            Ok((server, Some(response)))
+            Ok((address, Some(response)))
+        }
+        Ok((address, None)) if server.privacy() == Privacy::Cleartext => {
+            // DoT succeeded but returned no data, and cleartext retries are allowed
+            let cleartext = downgrade(server, "DoT returned no data");
+            match query_dns(&cleartext, query_data).await {
+                Ok(result) => Ok(result),
+                Err(_) => Ok((address, None)),
+            }
         }
-        Ok((server, None)) => {
-            // DoT succeeded but returned no data, try cleartext fallback
+        Ok((address, None)) => Ok((address, None)),
+        Err(e) if server.privacy() == Privacy::Strict => {
             println!(
-                "DoT returned no data for {}, falling back to cleartext DNS",
-                server
+                "DoT failed for {} ({}), not falling back in strict privacy mode",
+                server.address, e
             );
-            query_dns(dns_server, query_data).await
+            Err(e)
         }
         Err(e) => {
-            // DoT failed entirely, try cleartext fallback
-            println!(
-                "DoT failed for {} ({}), falling back to cleartext DNS",
-                dns_server, e
-            );
-            query_dns(dns_server, query_data).await
+            // The cleartext fallback always uses port 53, whatever port DoT uses
+            let cleartext = downgrade(server, &format!("DoT failed ({})", e));
+            query_dns(&cleartext, query_data).await
         }
     }
 }
@@ -133,9 +526,13 @@ pub async fn query_dns_with_fallback(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::tests::closed_port;
     use hickory_proto::op::{Message, Query};
-    use hickory_proto::rr::{Name, RecordType};
+    use hickory_proto::rr::Name;
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UdpSocket;
+    use tokio_rustls::rustls::RootCertStore;
 
     fn create_test_query() -> Vec<u8> {
         let mut message = Message::new();
@@ -146,6 +543,94 @@ mod tests {
         message.to_vec().unwrap()
     }
 
+    fn create_server(config: &str) -> ServerConfig {
+        toml::from_str(&format!("{}\ndescription = \"test\"", config)).unwrap()
+    }
+
+    #[test]
+    fn test_server_config_protocols() {
+        // Older configs select the protocol with `use_tls`, `use_quic` and `url`.
+        let server = create_server("address = \"1.1.1.1\"\nuse_tls = true");
+        assert_eq!(server.protocol(), Protocol::Dot);
+        assert_eq!(server.endpoint(), "dot://1.1.1.1:853");
+        assert_eq!(server.cleartext().endpoint(), "udp://1.1.1.1:53");
+
+        let server = create_server("address = \"2606:4700::1111\"\nprotocol = \"doh\"");
+        assert_eq!(server.url(), "https://[2606:4700::1111]:443/dns-query");
+
+        let server = create_server(
+            "address = \"9.9.9.9\"\nprotocol = \"doq\"\nport = 8853\ntls_name = \"dns.quad9.net\"",
+        );
+        assert_eq!(server.port(), 8853);
+        assert_eq!(server.tls_name(), "dns.quad9.net");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_udp_response_retries_over_tcp() {
+        use hickory_proto::op::MessageType;
+        use hickory_proto::rr::{rdata::A, RData, Record};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let socket = UdpSocket::bind(("127.0.0.1", port)).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (size, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let mut response = Message::from_bytes(&buf[..size]).unwrap();
+            response.set_message_type(MessageType::Response);
+            response.set_truncated(true);
+            socket
+                .send_to(&response.to_vec().unwrap(), peer)
+                .await
+                .unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut length_buf = [0u8; 2];
+            stream.read_exact(&mut length_buf).await.unwrap();
+            let mut query = vec![0; u16::from_be_bytes(length_buf) as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            let mut response = Message::from_bytes(&query).unwrap();
+            response.set_message_type(MessageType::Response);
+            let name = response.queries()[0].name().clone();
+            response.add_answer(Record::from_rdata(
+                name,
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ));
+            let response = response.to_vec().unwrap();
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+
+        let server = create_server(&format!("address = \"127.0.0.1\"\nport = {}", port));
+        let (_, response) = query_upstream(&server, create_test_query()).await.unwrap();
+        let message = Message::from_bytes(&response.unwrap()).unwrap();
+        assert!(!message.truncated());
+        assert_eq!(message.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_strict_privacy_never_downgrades() {
+        // DoT to a closed port fails immediately.
+        let server = create_server(&format!(
+            "address = \"127.0.0.1\"\nprotocol = \"dot\"\nport = {}\nprivacy = \"strict\"",
+            closed_port()
+        ));
+        assert!(query_upstream(&server, create_test_query()).await.is_err());
+
+        let server = create_server("address = \"127.0.0.1\"\nprivacy = \"strict\"");
+        let err = query_upstream(&server, create_test_query())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
     #[tokio::test]
     async fn test_fallback_functionality_exists() {
         // This test verifies that the fallback function exists and can be called
@@ -153,7 +638,8 @@ mod tests {
         let query_data = create_test_query();
 
         // Test with a non-existent server (should fail gracefully)
-        let result = query_dns_with_fallback("192.0.2.1", query_data).await;
+        let server = create_server("address = \"192.0.2.1\"\nprotocol = \"dot\"");
+        let result = query_dns_with_fallback(&server, query_data).await;
 
         // The function should return without panicking, even if it fails
         match result {
@@ -167,6 +653,195 @@ mod tests {
         }
     }
 
+    /// A rustls server config offering `alpn` with a self-signed certificate
+    /// for `localhost`, and that certificate for clients to trust.
+    fn self_signed_server(alpn: &[u8]) -> (tokio_rustls::rustls::ServerConfig, rcgen::Certificate) {
+        use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key_der = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+        let mut server_tls = tokio_rustls::rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key_der)
+            .unwrap();
+        server_tls.alpn_protocols = vec![alpn.to_vec()];
+        (server_tls, cert.cert)
+    }
+
+    /// What the local responders answer: the query, which must carry message
+    /// ID 0, as a NOERROR response with a single A record.
+    fn echo_answer(query: &[u8]) -> Vec<u8> {
+        use hickory_proto::op::MessageType;
+        use hickory_proto::rr::{rdata::A, RData, Record};
+
+        let mut response = Message::from_bytes(query).unwrap();
+        assert_eq!(response.id(), 0);
+        response.set_message_type(MessageType::Response);
+        let name = response.queries()[0].name().clone();
+        response.add_answer(Record::from_rdata(
+            name,
+            300,
+            RData::A(A::new(192, 0, 2, 53)),
+        ));
+        response.to_vec().unwrap()
+    }
+
+    /// A minimal DoQ responder with a self-signed certificate that echoes each
+    /// query back with `echo_answer`.
+    async fn spawn_doq_responder() -> (SocketAddr, ClientConfig) {
+        let (server_tls, cert) = self_signed_server(DOQ_ALPN);
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(
+            quinn::crypto::rustls::QuicServerConfig::try_from(server_tls).unwrap(),
+        ));
+        let endpoint =
+            quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let connection = incoming.await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                        let query = recv.read_to_end(4096).await.unwrap();
+                        let response = echo_answer(&query[2..]);
+                        send.write_all(&(response.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&response).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let mut client_tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_tls.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        (addr, client_tls)
+    }
+
+    #[tokio::test]
+    async fn test_doq_query_reuses_connection() {
+        let (addr, client_tls) = spawn_doq_responder().await;
+        let key = addr.to_string();
+
+        for _ in 0..2 {
+            let client_config = quic_client_config(client_tls.clone()).unwrap();
+            let response = query_quic_connection(
+                &key,
+                &[addr],
+                "localhost",
+                client_config,
+                create_test_query(),
+            )
+            .await
+            .unwrap();
+            let message = Message::from_bytes(&response).unwrap();
+            assert_eq!(message.id(), 12345);
+            assert_eq!(message.answers().len(), 1);
+        }
+
+        let connection = QUIC_CONNECTIONS.lock().await.get(&key).cloned().unwrap();
+        assert_eq!(connection.stats().frame_tx.stream, 2);
+    }
+
+    #[tokio::test]
+    async fn test_doq_falls_back_to_next_address() {
+        let (addr, client_tls) = spawn_doq_responder().await;
+        // Takes the QUIC handshake and never answers.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [silent.local_addr().unwrap(), addr];
+
+        let client_config = quic_client_config(client_tls).unwrap();
+        let key = format!("fallback-{}", addr);
+        let response = query_quic_connection(
+            &key,
+            &addrs,
+            "localhost",
+            client_config,
+            create_test_query(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers().len(), 1);
+        let connection = QUIC_CONNECTIONS.lock().await.get(&key).cloned().unwrap();
+        assert_eq!(connection.remote_address(), addr);
+    }
+
+    /// A minimal DoH responder with a self-signed certificate. `/dns-query`
+    /// answers each query with `echo_answer`; any other path answers 503. Returns the port, the CA file to trust and
+    /// the number of connections accepted.
+    async fn spawn_doh_responder() -> (u16, String, Arc<AtomicUsize>) {
+        use hyper::service::service_fn;
+        use hyper::Response;
+        use tokio_rustls::TlsAcceptor;
+
+        let (server_tls, cert) = self_signed_server(b"h2");
+        let dir = std::env::temp_dir().join(format!("dns-lb-doh-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_file = dir.join("ca.pem");
+        std::fs::write(&ca_file, cert.pem()).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_tls));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let tls_stream = acceptor.accept(stream).await.unwrap();
+                let service = service_fn(|request: Request<hyper::body::Incoming>| async move {
+                    if request.uri().path() != "/dns-query" {
+                        return Response::builder()
+                            .status(StatusCode::SERVICE_UNAVAILABLE)
+                            .body(Full::new(Bytes::new()));
+                    }
+                    let query = request.into_body().collect().await.unwrap().to_bytes();
+                    Response::builder()
+                        .header(header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+                        .body(Full::new(Bytes::from(echo_answer(&query))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(tls_stream), service),
+                );
+            }
+        });
+
+        (port, ca_file.display().to_string(), connections)
+    }
+
+    #[tokio::test]
+    async fn test_doh_query_reuses_connection() {
+        let (port, ca_file, connections) = spawn_doh_responder().await;
+        let server = create_server(&format!(
+            "address = \"127.0.0.1\"\nprotocol = \"doh\"\nport = {}\ntls_name = \"localhost\"\nca_file = {:?}",
+            port, ca_file
+        ));
+
+        for _ in 0..2 {
+            let (_, response) = query_dns_https(&server, create_test_query()).await.unwrap();
+            let message = Message::from_bytes(&response.unwrap()).unwrap();
+            assert_eq!(message.id(), 12345);
+            assert_eq!(message.answers().len(), 1);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let broken = create_server(&format!(
+            "address = \"127.0.0.1\"\nurl = \"https://127.0.0.1:{}/broken\"\ntls_name = \"localhost\"\nca_file = {:?}",
+            port, ca_file
+        ));
+        let err = query_dns_https(&broken, create_test_query())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("503"));
+    }
+
     #[test]
     fn test_create_dns_query() {
         // Test that we can create a valid DNS query
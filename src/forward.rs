@@ -0,0 +1,215 @@
+use crate::config::{Config, ConsensusRule, SyntheticResponse};
+use hickory_proto::rr::RecordType;
+use std::str::FromStr;
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The rule for the longest of the `rules` suffixes that `name` is in,
+/// matching on label boundaries. `rules` must be sorted longest first.
+fn longest_match<'a, T>(rules: &'a [(String, T)], name: &str) -> Option<&'a (String, T)> {
+    let name = normalize(name);
+    rules.iter().find(|(suffix, _)| {
+        suffix.is_empty()
+            || name == *suffix
+            || (name.ends_with(suffix.as_str()) && name[..name.len() - suffix.len()].ends_with('.'))
+    })
+}
+
+/// Builds `(suffix, value)` rules for every domain, sorted longest first.
+fn suffix_rules<R, T: Clone>(
+    rules: &[R],
+    domains: impl Fn(&R) -> &[String],
+    value: impl Fn(&R) -> T,
+) -> Vec<(String, T)> {
+    let mut suffixes: Vec<(String, T)> = rules
+        .iter()
+        .flat_map(|rule| {
+            let value = value(rule);
+            domains(rule)
+                .iter()
+                .map(move |domain| (normalize(domain), value.clone()))
+        })
+        .collect();
+    suffixes.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+    suffixes
+}
+
+/// What an internal domain forwards, and the answer for everything else.
+#[derive(Clone)]
+pub struct Internal {
+    pub allow: Vec<RecordType>,
+    pub response: SyntheticResponse,
+}
+
+/// Picks the upstream group for a query name from the `[[forward]]` rules,
+/// by longest matching domain suffix, or the default group otherwise, and
+/// the `[[consensus]]` and `[[internal_domains]]` rules that apply to it.
+pub struct Router {
+    /// Domain suffixes with the indices of their group's upstreams, longest first.
+    rules: Vec<(String, Vec<usize>)>,
+    default: Vec<usize>,
+    /// Domain suffixes with their consensus rule, longest first.
+    consensus: Vec<(String, ConsensusRule)>,
+    /// Internal domain suffixes with what they forward, longest first.
+    internal: Vec<(String, Internal)>,
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Self {
+        let group = |name: &str| -> Vec<usize> {
+            config
+                .servers
+                .iter()
+                .enumerate()
+                .filter(|(_, server)| server.group() == name)
+                .map(|(index, _)| index)
+                .collect()
+        };
+
+        Self {
+            rules: suffix_rules(
+                &config.forward,
+                |rule| &rule.domains,
+                |rule| group(&rule.group),
+            ),
+            default: group(&config.default_group),
+            consensus: suffix_rules(&config.consensus, |rule| &rule.domains, Clone::clone),
+            internal: suffix_rules(
+                &config.internal_domains,
+                |rule| &rule.domains,
+                |rule| Internal {
+                    allow: rule
+                        .allow
+                        .iter()
+                        .filter_map(|record_type| {
+                            RecordType::from_str(&record_type.to_ascii_uppercase()).ok()
+                        })
+                        .collect(),
+                    response: rule.response,
+                },
+            ),
+        }
+    }
+
+    /// Indices of the upstreams that should answer `name`.
+    pub fn route(&self, name: &str) -> &[usize] {
+        longest_match(&self.rules, name)
+            .map(|(_, upstreams)| upstreams.as_slice())
+            .unwrap_or(&self.default)
+    }
+
+    /// The consensus rule for `name`, if its answers need confirming.
+    pub fn consensus(&self, name: &str) -> Option<&ConsensusRule> {
+        longest_match(&self.consensus, name).map(|(_, rule)| rule)
+    }
+
+    /// A consensus domain, or a forwarded domain below one, whose group has
+    /// fewer upstreams than its rule requires, with `required` and the group size.
+    pub fn unsatisfiable_consensus(&self) -> Option<(&str, usize, usize)> {
+        let domains = self.consensus.iter().map(|(suffix, _)| suffix);
+        domains
+            .chain(self.rules.iter().map(|(suffix, _)| suffix))
+            .find_map(|domain| {
+                let required = self.consensus(domain)?.required;
+                let available = self.route(domain).len();
+                (required > available).then_some((domain.as_str(), required, available))
+            })
+    }
+
+    /// The internal domain `name` is in, if any, and what it forwards.
+    pub fn internal(&self, name: &str) -> Option<&(String, Internal)> {
+        longest_match(&self.internal, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_suffix_match() {
+        let config: Config = toml::from_str(
+            r#"
+            [[forward]]
+            domains = ["cluster.local", "10.in-addr.arpa"]
+            group = "cluster"
+
+            [[forward]]
+            domains = ["svc.cluster.local."]
+            group = "services"
+
+            [[consensus]]
+            domains = ["bank.example"]
+            required = 2
+
+            [[servers]]
+            address = "1.1.1.1"
+            description = "public"
+
+            [[servers]]
+            address = "10.152.183.10"
+            group = "cluster"
+            description = "cluster"
+
+            [[servers]]
+            address = "10.152.183.11"
+            group = "services"
+            description = "services"
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(&config);
+
+        assert_eq!(router.route("postgresql.invoice.svc.cluster.local."), &[2]);
+        assert_eq!(router.route("node1.cluster.local."), &[1]);
+        assert_eq!(router.route("CLUSTER.LOCAL"), &[1]);
+        assert_eq!(router.route("4.3.2.10.in-addr.arpa."), &[1]);
+        assert_eq!(router.route("www.example.com."), &[0]);
+        // Suffixes only match on label boundaries.
+        assert_eq!(router.route("notcluster.local."), &[0]);
+        assert!(router.consensus("www.example.com.").is_none());
+        assert_eq!(router.consensus("login.BANK.example.").unwrap().required, 2);
+        assert_eq!(
+            router.unsatisfiable_consensus(),
+            Some(("bank.example", 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_consensus_within_forwarded_domain() {
+        let config: Config = toml::from_str(
+            r#"
+            [[consensus]]
+            domains = ["example"]
+            required = 2
+
+            [[forward]]
+            domains = ["corp.example"]
+            group = "corp"
+
+            [[servers]]
+            address = "1.1.1.1"
+            description = "public"
+
+            [[servers]]
+            address = "8.8.8.8"
+            description = "public"
+
+            [[servers]]
+            address = "10.0.0.53"
+            group = "corp"
+            description = "corp"
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(&config);
+
+        assert_eq!(router.route("www.example."), &[0, 1]);
+        assert_eq!(
+            router.unsatisfiable_consensus(),
+            Some(("corp.example", 2, 1))
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::config::{HealthConfig, ServerConfig, DNS_TIMEOUT};
+use crate::dns::query::query_upstream;
+use crate::stats::STATS;
+use hickory_proto::op::{Message, Query};
+use hickory_proto::rr::{Name, RecordType};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Circuit breaker state of one upstream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Circuit {
+    /// Healthy, counting consecutive failures.
+    Closed(u32),
+    /// Skipped until the given time.
+    Open(Instant),
+    /// Letting a single trial query through to test recovery.
+    HalfOpen,
+}
+
+/// Tracks which upstreams are reachable, from real traffic and periodic
+/// probes, so that dead upstreams are skipped instead of waited out.
+pub struct Health {
+    config: HealthConfig,
+    names: Vec<String>,
+    circuits: Mutex<Vec<Circuit>>,
+}
+
+impl Health {
+    pub fn new(config: &HealthConfig, servers: &[ServerConfig]) -> Self {
+        Self {
+            config: config.clone(),
+            names: servers
+                .iter()
+                .map(|server| format!("{} ({})", server.address, server.description))
+                .collect(),
+            circuits: Mutex::new(vec![Circuit::Closed(0); servers.len()]),
+        }
+    }
+
+    fn transition(&self, index: usize, circuits: &mut [Circuit], next: Circuit) {
+        let previous = std::mem::replace(&mut circuits[index], next);
+        match (previous, next) {
+            (Circuit::Closed(_), Circuit::Open(_)) => {
+                let count = STATS.record_circuit_open();
+                println!(
+                    "Circuit open for {}, skipping it for {}s ({} circuits opened so far)",
+                    self.names[index], self.config.open_duration, count
+                );
+            }
+            (Circuit::HalfOpen, Circuit::Open(_)) => {
+                println!("{} is still failing, circuit open again", self.names[index]);
+            }
+            (Circuit::Open(_), Circuit::HalfOpen) => {
+                println!(
+                    "Circuit half-open for {}, testing recovery",
+                    self.names[index]
+                );
+            }
+            (Circuit::Open(_) | Circuit::HalfOpen, Circuit::Closed(_)) => {
+                println!("{} recovered, circuit closed", self.names[index]);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether a query may be sent to the upstream. Once an open circuit has
+    /// waited `open_duration`, one trial query is let through.
+    pub fn allow(&self, index: usize) -> bool {
+        let Ok(mut circuits) = self.circuits.lock() else {
+            return true;
+        };
+        match circuits[index] {
+            Circuit::Closed(_) => true,
+            Circuit::Open(until) if Instant::now() >= until => {
+                self.transition(index, &mut circuits, Circuit::HalfOpen);
+                true
+            }
+            Circuit::Open(_) | Circuit::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&self, index: usize) {
+        if let Ok(mut circuits) = self.circuits.lock() {
+            self.transition(index, &mut circuits, Circuit::Closed(0));
+        }
+    }
+
+    pub fn record_failure(&self, index: usize) {
+        let Ok(mut circuits) = self.circuits.lock() else {
+            return;
+        };
+        let open = Circuit::Open(Instant::now() + Duration::from_secs(self.config.open_duration));
+        let next = match circuits[index] {
+            Circuit::Closed(failures) if failures + 1 >= self.config.failure_threshold => open,
+            Circuit::Closed(failures) => Circuit::Closed(failures + 1),
+            Circuit::HalfOpen => open,
+            Circuit::Open(until) => Circuit::Open(until),
+        };
+        self.transition(index, &mut circuits, next);
+    }
+
+    /// A trial query that was cancelled before it finished proves nothing,
+    /// so the circuit goes back to open and the next query is the trial.
+    pub fn record_abandoned(&self, index: usize) {
+        if let Ok(mut circuits) = self.circuits.lock() {
+            if circuits[index] == Circuit::HalfOpen {
+                circuits[index] = Circuit::Open(Instant::now());
+            }
+        }
+    }
+
+    fn probe_query(&self) -> Option<Vec<u8>> {
+        let name = Name::from_str(&self.config.probe_name).ok()?;
+        let record_type =
+            RecordType::from_str(&self.config.probe_type.to_ascii_uppercase()).ok()?;
+        let mut message = Message::new();
+        message.set_recursion_desired(true);
+        message.add_query(Query::query(name, record_type));
+        message.to_vec().ok()
+    }
+
+    /// Probe every upstream every `interval` seconds until shutdown. Any
+    /// response counts as alive; only errors and timeouts count as failures.
+    pub fn spawn_probes(
+        self: &Arc<Self>,
+        servers: Arc<Vec<ServerConfig>>,
+        mut shutdown: tokio::sync::broadcast::Receiver<()>,
+    ) {
+        if self.config.interval == 0 {
+            return;
+        }
+        let Some(probe) = self.probe_query() else {
+            eprintln!(
+                "Warning: invalid health probe {} {}, probes disabled",
+                self.config.probe_name, self.config.probe_type
+            );
+            return;
+        };
+        let health = Arc::clone(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(health.config.interval)) => {
+                        for (index, server) in servers.iter().enumerate() {
+                            let health = Arc::clone(&health);
+                            let server = server.clone();
+                            let probe = probe.clone();
+                            tokio::spawn(async move {
+                                match tokio::time::timeout(
+                                    Duration::from_secs(DNS_TIMEOUT),
+                                    query_upstream(&server, probe),
+                                )
+                                .await
+                                {
+                                    Ok(Ok(_)) => health.record_success(index),
+                                    _ => health.record_failure(index),
+                                }
+                            });
+                        }
+                    }
+
+                    _ = shutdown.recv() => {
+                        println!("Health check task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let config: HealthConfig =
+            toml::from_str("failure_threshold = 2\nopen_duration = 0").unwrap();
+        let server: ServerConfig =
+            toml::from_str("address = \"192.0.2.1\"\ndescription = \"test\"").unwrap();
+        let health = Health::new(&config, &[server]);
+
+        health.record_failure(0);
+        assert!(health.allow(0));
+        health.record_failure(0);
+        assert!(matches!(
+            health.circuits.lock().unwrap()[0],
+            Circuit::Open(_)
+        ));
+
+        // The open duration has passed: one trial, then nothing until it reports back.
+        assert!(health.allow(0));
+        assert!(!health.allow(0));
+        health.record_failure(0);
+        assert!(matches!(
+            health.circuits.lock().unwrap()[0],
+            Circuit::Open(_)
+        ));
+
+        assert!(health.allow(0));
+        health.record_success(0);
+        assert_eq!(health.circuits.lock().unwrap()[0], Circuit::Closed(0));
+        assert!(health.allow(0));
+    }
+}
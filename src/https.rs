@@ -0,0 +1,385 @@
+use crate::config::TCP_IDLE_TIMEOUT;
+use crate::server::Server;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hickory_proto::op::{Edns, Message, Query};
+use hickory_proto::rr::{Name, Record, RecordType};
+use hickory_proto::serialize::binary::BinDecodable;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+const DNS_QUERY_PATH: &str = "/dns-query";
+const MAX_BODY_SIZE: usize = 65535;
+
+/// DNS over HTTPS for clients: RFC 8484 wire format on `/dns-query` via GET
+/// and POST, and the `application/dns-json` API for browsers and scripts.
+pub async fn run_https(
+    server: Server,
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> std::io::Result<()> {
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, peer)) => {
+                        let server = server.clone();
+                        let acceptor = acceptor.clone();
+                        let mut shutdown_handler = shutdown.resubscribe();
+
+                        tokio::spawn(async move {
+                            let connection = async {
+                                let tls_stream = tokio::time::timeout(
+                                    Duration::from_secs(TCP_IDLE_TIMEOUT),
+                                    acceptor.accept(stream),
+                                )
+                                .await
+                                .map_err(|_| std::io::Error::new(
+                                    std::io::ErrorKind::TimedOut,
+                                    "TLS handshake timed out",
+                                ))??;
+
+                                let service = service_fn(move |request| {
+                                    handle_http(server.clone(), request)
+                                });
+                                auto::Builder::new(TokioExecutor::new())
+                                    .serve_connection(TokioIo::new(tls_stream), service)
+                                    .await
+                                    .map_err(std::io::Error::other)
+                            };
+
+                            tokio::select! {
+                                result = connection => {
+                                    if let Err(e) = result {
+                                        eprintln!("HTTPS connection from {} failed: {}", peer, e);
+                                    }
+                                }
+                                _ = shutdown_handler.recv() => {
+                                    println!("HTTPS connection handler shutting down");
+                                }
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Error accepting: {}", e),
+                }
+            }
+            _ = shutdown.recv() => {
+                println!("HTTPS server loop shutting down");
+                break;
+            }
+        }
+    }
+
+    println!("HTTPS server shutdown complete");
+    Ok(())
+}
+
+async fn handle_http(
+    server: Server,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = match Limited::new(body, MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE)),
+    };
+
+    let header_value = |name: header::HeaderName| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_string()
+    };
+
+    Ok(respond(
+        &server,
+        &parts.method,
+        parts.uri.path(),
+        parts.uri.query().unwrap_or(""),
+        &header_value(header::CONTENT_TYPE),
+        &header_value(header::ACCEPT),
+        body,
+    )
+    .await)
+}
+
+async fn respond(
+    server: &Server,
+    method: &Method,
+    path: &str,
+    query: &str,
+    content_type: &str,
+    accept: &str,
+    body: Bytes,
+) -> Response<Full<Bytes>> {
+    if path != DNS_QUERY_PATH {
+        return error_response(StatusCode::NOT_FOUND);
+    }
+
+    let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+
+    match *method {
+        Method::GET if param("dns").is_some() => {
+            match URL_SAFE_NO_PAD.decode(param("dns").unwrap_or("").trim_end_matches('=')) {
+                Ok(query) => wire_response(server, &query).await,
+                Err(_) => error_response(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::GET if param("name").is_some() || accept.contains(DNS_JSON) => {
+            json_response(
+                server,
+                param("name"),
+                param("type"),
+                param("do"),
+                param("cd"),
+            )
+            .await
+        }
+        Method::GET => error_response(StatusCode::BAD_REQUEST),
+        Method::POST if content_type.starts_with(DNS_MESSAGE) => wire_response(server, &body).await,
+        Method::POST => error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+fn error_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(
+        status.canonical_reason().unwrap_or("Error"),
+    )));
+    *response.status_mut() = status;
+    response
+}
+
+// HTTP caches may keep the answer as long as its shortest TTL (RFC 8484 section 5.1).
+fn min_ttl(message: &Message) -> u32 {
+    message
+        .answers()
+        .iter()
+        .chain(message.name_servers())
+        .map(Record::ttl)
+        .min()
+        .unwrap_or(0)
+}
+
+async fn wire_response(server: &Server, query: &[u8]) -> Response<Full<Bytes>> {
+    let Ok(request) = Message::from_bytes(query) else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+
+    match server.handle_request(query).await {
+        Some(response_data) => {
+            let max_age = Message::from_bytes(&response_data)
+                .map(|message| min_ttl(&message))
+                .unwrap_or(0);
+            let mut response = Response::new(Full::new(Bytes::from(response_data)));
+            let headers = response.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static(DNS_MESSAGE),
+            );
+            if let Ok(value) = header::HeaderValue::from_str(&format!("max-age={}", max_age)) {
+                headers.insert(header::CACHE_CONTROL, value);
+            }
+            response
+        }
+        None => {
+            eprintln!("No response for DoH query {}", request.id());
+            error_response(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+fn is_true(value: Option<&str>) -> bool {
+    matches!(value, Some("1") | Some("true"))
+}
+
+fn records_json(records: &[Record]) -> Value {
+    records
+        .iter()
+        .map(|record| {
+            json!({
+                "name": record.name().to_ascii(),
+                "type": u16::from(record.record_type()),
+                "TTL": record.ttl(),
+                "data": record.data().to_string(),
+            })
+        })
+        .collect()
+}
+
+async fn json_response(
+    server: &Server,
+    name: Option<&str>,
+    record_type: Option<&str>,
+    dnssec_ok: Option<&str>,
+    checking_disabled: Option<&str>,
+) -> Response<Full<Bytes>> {
+    // Names in the JSON API are usually given without the trailing dot
+    let Some(Ok(name)) =
+        name.map(|name| Name::from_str(&format!("{}.", name.trim_end_matches('.'))))
+    else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+    let record_type = match record_type {
+        None => RecordType::A,
+        Some(value) => match value.parse::<u16>() {
+            Ok(code) => RecordType::from(code),
+            Err(_) => match RecordType::from_str(&value.to_ascii_uppercase()) {
+                Ok(record_type) => record_type,
+                Err(_) => return error_response(StatusCode::BAD_REQUEST),
+            },
+        },
+    };
+
+    let mut query = Message::new();
+    query.set_recursion_desired(true);
+    query.set_checking_disabled(is_true(checking_disabled));
+    query.add_query(Query::query(name, record_type));
+    if is_true(dnssec_ok) {
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true);
+        query.set_edns(edns);
+    }
+    let Ok(query) = query.to_vec() else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+
+    let Some(Ok(message)) = server
+        .handle_request(&query)
+        .await
+        .map(|response_data| Message::from_bytes(&response_data))
+    else {
+        return error_response(StatusCode::BAD_GATEWAY);
+    };
+
+    let mut body = json!({
+        "Status": u16::from(message.response_code()),
+        "TC": message.truncated(),
+        "RD": message.recursion_desired(),
+        "RA": message.recursion_available(),
+        "AD": message.authentic_data(),
+        "CD": message.checking_disabled(),
+        "Question": message
+            .queries()
+            .iter()
+            .map(|q| json!({ "name": q.name().to_ascii(), "type": u16::from(q.query_type()) }))
+            .collect::<Vec<_>>(),
+    });
+    if !message.answers().is_empty() {
+        body["Answer"] = records_json(message.answers());
+    }
+    if !message.name_servers().is_empty() {
+        body["Authority"] = records_json(message.name_servers());
+    }
+
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(DNS_JSON),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::tests::create_local_query;
+
+    async fn body_bytes(response: Response<Full<Bytes>>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_wire_format_get_and_post() {
+        let server = Server::new(1024, &Config::default());
+        let query = create_local_query(0);
+
+        let get = format!("dns={}", URL_SAFE_NO_PAD.encode(&query));
+        let response = respond(
+            &server,
+            &Method::GET,
+            DNS_QUERY_PATH,
+            &get,
+            "",
+            DNS_MESSAGE,
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], DNS_MESSAGE);
+        let message = Message::from_bytes(&body_bytes(response).await).unwrap();
+        assert_eq!(message.queries().len(), 1);
+
+        let response = respond(
+            &server,
+            &Method::POST,
+            DNS_QUERY_PATH,
+            "",
+            DNS_MESSAGE,
+            DNS_MESSAGE,
+            Bytes::from(query),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = respond(
+            &server,
+            &Method::POST,
+            DNS_QUERY_PATH,
+            "",
+            "text/plain",
+            "",
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_json_api() {
+        let server = Server::new(1024, &Config::default());
+        let response = respond(
+            &server,
+            &Method::GET,
+            DNS_QUERY_PATH,
+            "name=postgresql.invoice.svc.cluster.local&type=AAAA",
+            "",
+            DNS_JSON,
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["Status"], 0);
+        assert_eq!(
+            body["Question"][0]["name"],
+            "postgresql.invoice.svc.cluster.local."
+        );
+        assert_eq!(body["Question"][0]["type"], 28);
+    }
+}
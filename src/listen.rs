@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Expand a listen specification into socket addresses.
+///
+/// Accepts `ip:port`, `[ipv6]:port`, a bare IP (which uses `default_port`),
+/// and `*` or `*:port` as a wildcard for both `0.0.0.0` and `[::]`.
+pub fn parse_listen_addr(spec: &str, default_port: u16) -> Result<Vec<SocketAddr>> {
+    let spec = spec.trim();
+
+    if let Some(rest) = spec.strip_prefix('*') {
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse()?,
+            None if rest.is_empty() => default_port,
+            None => return Err(anyhow!("Invalid listen address: {}", spec)),
+        };
+        return Ok(vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+        ]);
+    }
+
+    if let Ok(addr) = spec.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+
+    let ip = spec
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow!("Invalid listen address: {}", spec))?;
+    Ok(vec![SocketAddr::new(ip, default_port)])
+}
+
+/// Expand every listen specification, warning about and skipping invalid ones.
+pub fn parse_listen_addrs(specs: &[String], default_port: u16) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for spec in specs {
+        match parse_listen_addr(spec, default_port) {
+            Ok(parsed) => addrs.extend(parsed),
+            Err(e) => eprintln!("Warning: ignoring listen address {}: {}", spec, e),
+        }
+    }
+    addrs
+}
+
+/// Default listen addresses when neither the config nor the CLI specify any.
+pub fn loopback_addrs(port: u16) -> Vec<SocketAddr> {
+    vec![
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port),
+    ]
+}
+
+// IPv6 sockets are bound v6-only so `0.0.0.0` and `[::]` can share a port.
+// SO_REUSEADDR is only set for TCP, where it lets a restart rebind while old
+// connections sit in TIME_WAIT; on UDP it would let a second process share
+// the port and steal queries.
+fn new_socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if protocol == Protocol::TCP {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+pub fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP)?;
+    UdpSocket::from_std(socket.into())
+}
+
+pub fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            parse_listen_addr("192.168.1.10:53", 5353).unwrap(),
+            vec!["192.168.1.10:53".parse().unwrap()]
+        );
+        assert_eq!(
+            parse_listen_addr("::1", 5353).unwrap(),
+            vec!["[::1]:5353".parse().unwrap()]
+        );
+        assert_eq!(
+            parse_listen_addr("*:53", 5353).unwrap(),
+            vec!["0.0.0.0:53".parse().unwrap(), "[::]:53".parse().unwrap()]
+        );
+        assert!(parse_listen_addr("localhost:53", 5353).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_port_is_exclusive() {
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        assert!(bind_udp(addr).is_err());
+    }
+}
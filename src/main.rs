@@ -1,13 +1,21 @@
+mod balancer;
 mod config;
 mod dns;
+mod forward;
+mod health;
+mod https;
+mod listen;
 mod server;
+mod stats;
+mod tls;
 
-use anyhow::{Ok, Result};
+use anyhow::Result;
 use clap::{Parser, Subcommand};
-use config::Config;
+use config::{Config, Privacy, DOH_PORT, DOT_PORT, UDP_RECV_BUFFER_SIZE};
 use nix::unistd::{getuid, setuid, Uid};
 use server::Server;
-use tokio::{net::UdpSocket, signal};
+use tls::ReloadingCertifiedKey;
+use tokio::{signal, task::JoinSet};
 
 // Command line arguments with clap.
 #[derive(Parser)]
@@ -26,6 +34,10 @@ enum Commands {
 
         #[arg(short, long, default_value_t = 5353)]
         port: u16,
+
+        /// Address to listen on, may be repeated. Overrides `listen` in the config.
+        #[arg(short, long)]
+        listen: Vec<String>,
     },
     Example,
 }
@@ -42,45 +54,168 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Run { config, port } => {
-            let dns_servers = Config::load(&config)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
-                .servers;
+        Commands::Run {
+            config,
+            port,
+            listen,
+        } => {
+            let config = Config::load(&config).map_err(std::io::Error::other)?;
+            let dns_servers = &config.servers;
+
+            // Report bad CA bundles and pins now rather than on the first query.
+            for dns_server in dns_servers.iter().filter(|s| s.uses_tls()) {
+                tls::client_config(dns_server, Vec::new())?;
+            }
+            if !dns_servers
+                .iter()
+                .any(|server| server.group() == config.default_group)
+            {
+                eprintln!(
+                    "Warning: default group {} has no servers, names without a forwarding rule get no answer",
+                    config.default_group
+                );
+            }
+            for dns_server in dns_servers.iter() {
+                if !dns_server.uses_tls() && dns_server.privacy() == Privacy::Strict {
+                    eprintln!(
+                        "Warning: cleartext upstream {} is never queried in strict privacy mode",
+                        dns_server.address
+                    );
+                }
+            }
 
-            let ipv4_addr = format!("127.0.0.1:{}", port);
-            let ipv6_addr = format!("[::1]:{}", port);
+            let listen = if listen.is_empty() {
+                config.listen.clone()
+            } else {
+                listen
+            };
 
-            let socket_v4 = UdpSocket::bind(&ipv4_addr).await?;
-            println!(
-                "DNS forwarder listening on IPv4: {}",
-                socket_v4.local_addr()?
-            );
+            let listen_addrs = if listen.is_empty() {
+                listen::loopback_addrs(port)
+            } else {
+                listen::parse_listen_addrs(&listen, port)
+            };
 
-            let socket_v6 = UdpSocket::bind(&ipv6_addr).await?;
-            println!(
-                "DNS forwarder listening on IPv6: {}",
-                socket_v6.local_addr()?
-            );
+            let mut udp_sockets = Vec::new();
+            let mut tcp_listeners = Vec::new();
+            for addr in listen_addrs {
+                match listen::bind_udp(addr) {
+                    Ok(socket) => {
+                        println!("DNS forwarder listening on UDP: {}", socket.local_addr()?);
+                        udp_sockets.push(socket);
+                    }
+                    Err(e) => eprintln!("Warning: could not bind UDP {}: {}", addr, e),
+                }
+                match listen::bind_tcp(addr) {
+                    Ok(listener) => {
+                        println!("DNS forwarder listening on TCP: {}", listener.local_addr()?);
+                        tcp_listeners.push(listener);
+                    }
+                    Err(e) => eprintln!("Warning: could not bind TCP {}: {}", addr, e),
+                }
+            }
+
+            let mut tls_listeners = Vec::new();
+            let mut tls_certificate = None;
+            if let Some(tls_config) = &config.tls {
+                let certified_key = ReloadingCertifiedKey::load(&tls_config.cert, &tls_config.key)?;
+                for addr in listen::parse_listen_addrs(&tls_config.listen, DOT_PORT) {
+                    match listen::bind_tcp(addr) {
+                        Ok(listener) => {
+                            println!("DNS forwarder listening on TLS: {}", listener.local_addr()?);
+                            tls_listeners.push(listener);
+                        }
+                        Err(e) => eprintln!("Warning: could not bind TLS {}: {}", addr, e),
+                    }
+                }
+                tls_certificate = Some(certified_key);
+            }
 
+            let mut https_listeners = Vec::new();
+            let mut https_certificate = None;
+            if let Some(https_config) = &config.https {
+                let certified_key =
+                    ReloadingCertifiedKey::load(&https_config.cert, &https_config.key)?;
+                for addr in listen::parse_listen_addrs(&https_config.listen, DOH_PORT) {
+                    match listen::bind_tcp(addr) {
+                        Ok(listener) => {
+                            println!(
+                                "DNS forwarder listening on HTTPS: {}",
+                                listener.local_addr()?
+                            );
+                            https_listeners.push(listener);
+                        }
+                        Err(e) => eprintln!("Warning: could not bind HTTPS {}: {}", addr, e),
+                    }
+                }
+                https_certificate = Some(certified_key);
+            }
+
+            if udp_sockets.is_empty()
+                && tcp_listeners.is_empty()
+                && tls_listeners.is_empty()
+                && https_listeners.is_empty()
+            {
+                return Err(anyhow::anyhow!("Could not bind any listen address"));
+            }
+
+            // Every certificate and key, for the listeners and for upstream
+            // client authentication, is loaded by now: key files are usually
+            // readable by root only.
             drop_privileges()?;
 
-            let server_v4 = Server::new(socket_v4, 1024, dns_servers.clone());
-            let server_v6 = Server::new(socket_v6, 1024, dns_servers);
+            let server = Server::new(UDP_RECV_BUFFER_SIZE, &config);
 
             // Shutdown-channel
-            let (shutdown_tx, shutdown_rx_v4) = tokio::sync::broadcast::channel(1);
-            let shutdown_rx_v6 = shutdown_rx_v4.resubscribe();
+            let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+            server.spawn_cache_cleanup(shutdown_rx.resubscribe());
+            server.spawn_health_checks(shutdown_rx.resubscribe());
 
-            let server_handle = tokio::spawn(async move {
-                tokio::select! {
-                    _ = server_v4.run(shutdown_rx_v4) => {
-                            println!("IPv4 server stopped normally");
-                    }
+            let mut servers = JoinSet::new();
+            for socket in udp_sockets {
+                servers.spawn(server.clone().run(socket, shutdown_rx.resubscribe()));
+            }
+            for listener in tcp_listeners {
+                servers.spawn(server.clone().run_tcp(listener, shutdown_rx.resubscribe()));
+            }
+            if let Some(certified_key) = tls_certificate {
+                certified_key.spawn_reload(shutdown_rx.resubscribe());
+                let acceptor = tls::acceptor(certified_key, vec![b"dot".to_vec()]);
+                for listener in tls_listeners {
+                    servers.spawn(server.clone().run_tls(
+                        listener,
+                        acceptor.clone(),
+                        shutdown_rx.resubscribe(),
+                    ));
+                }
+            }
 
-                    _ = server_v6.run(shutdown_rx_v6) => {
-                            println!("IPv6 server stopped normally");
+            if let Some(certified_key) = https_certificate {
+                certified_key.spawn_reload(shutdown_rx.resubscribe());
+                let acceptor =
+                    tls::acceptor(certified_key, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+                for listener in https_listeners {
+                    servers.spawn(https::run_https(
+                        server.clone(),
+                        listener,
+                        acceptor.clone(),
+                        shutdown_rx.resubscribe(),
+                    ));
+                }
+            }
+
+            let server_handle = tokio::spawn(async move {
+                // Stop as soon as any listener exits, as before with a single socket pair.
+                if let Some(result) = servers.join_next().await {
+                    match result {
+                        Ok(Ok(())) => {
+                            println!("Server stopped normally")
+                        }
+                        Ok(Err(e)) => eprintln!("Server stopped: {}", e),
+                        Err(e) => eprintln!("Server task failed: {}", e),
                     }
                 }
+                servers.shutdown().await;
             });
 
             signal::ctrl_c().await?;
@@ -97,11 +232,11 @@ async fn main() -> Result<()> {
                 }
             }
 
+            println!("Statistics: {}", stats::STATS);
             println!("Server shutdown complete");
         }
         Commands::Example => {
             println!(
-                "{}",
                 r#"[[servers]]
 address = "1.1.1.1"
 use_tls = true
@@ -112,6 +247,12 @@ address = "8.8.8.8"
 use_tls = true
 description = "Google DNS"
 
+[[servers]]
+address = "9.9.9.9"
+protocol = "doq"
+tls_name = "dns.quad9.net"
+description = "Quad9 DNS over QUIC"
+
 [[servers]]
 address = "10.152.183.10"
 use_tls = false
@@ -1,190 +1,692 @@
-use crate::config::{ServerConfig, CACHE_TTL, DNS_TIMEOUT, KUBERNETES_DOMAIN};
+use crate::balancer::Balancer;
+use crate::config::{
+    Config, ConsensusRule, NegativePolicy, ServerConfig, SyntheticResponse, CACHE_TTL, DNS_TIMEOUT,
+    INTERNAL_SOA_TTL, TCP_IDLE_TIMEOUT, TCP_PIPELINE_LIMIT,
+};
 use crate::dns::cache::DnsCache;
-use crate::dns::query::{query_dns, query_dns_with_fallback};
-use hickory_proto::op::{Message, MessageType, ResponseCode};
+use crate::dns::coalesce::Coalescer;
+use crate::dns::edns;
+use crate::dns::query::{is_negative, query_upstream};
+use crate::forward::Router;
+use crate::health::Health;
+use crate::stats::STATS;
+use hickory_proto::op::{Edns, Message, MessageType, ResponseCode};
+use hickory_proto::rr::rdata::SOA;
+use hickory_proto::rr::{Name, RData, Record};
 use hickory_proto::serialize::binary::BinDecodable;
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::UdpSocket;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
 
+/// What one upstream, by index, made of a query.
+enum Reply {
+    /// A positive answer, already carrying the client's query ID.
+    Answer(usize, Vec<u8>),
+    /// An authoritative NXDOMAIN or NODATA answer and how long it may be cached.
+    Negative(usize, Vec<u8>, Duration),
+    /// An error, a timeout or a response without anything to forward.
+    Failed(usize, Failure),
+}
+
+/// Why an upstream did not contribute to the answer.
+enum Failure {
+    /// The query could not be sent or the connection failed.
+    Network(String),
+    /// No reply within `DNS_TIMEOUT`.
+    Timeout,
+    /// A reply without answers, e.g. SERVFAIL or REFUSED.
+    NoAnswer,
+    /// Skipped because its circuit is open.
+    CircuitOpen,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Network(e) => write!(f, "{}", e),
+            Failure::Timeout => write!(f, "timed out"),
+            Failure::NoAnswer => write!(f, "no answer"),
+            Failure::CircuitOpen => write!(f, "circuit open"),
+        }
+    }
+}
+
+/// The upstream queries spawned for one request. Those still running when
+/// the request has its response, or is itself abandoned, are aborted so they
+/// hold no sockets or handshakes and start no fallbacks for nobody.
+struct UpstreamTasks(Vec<tokio::task::JoinHandle<()>>);
+
+impl Drop for UpstreamTasks {
+    fn drop(&mut self) {
+        let running = self.0.iter().filter(|task| !task.is_finished()).count();
+        for task in &self.0 {
+            task.abort();
+        }
+        if running > 0 {
+            STATS.record_cancelled_queries(running as u64);
+        }
+    }
+}
+
+/// One upstream query in flight. If its task is aborted before the query
+/// finished, what is known still reaches the balancer and the circuit
+/// breaker: the time spent is a lower bound on the upstream's latency, and a
+/// query still running at the request's deadline has timed out.
+struct Attempt {
+    index: usize,
+    started: Instant,
+    deadline: tokio::time::Instant,
+    balancer: Arc<Balancer>,
+    health: Arc<Health>,
+    finished: bool,
+}
+
+impl Attempt {
+    fn finish(mut self, succeeded: bool) {
+        self.finished = true;
+        self.balancer
+            .record_latency(self.index, self.started.elapsed());
+        if succeeded {
+            self.health.record_success(self.index);
+        } else {
+            self.health.record_failure(self.index);
+        }
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.balancer
+            .record_unfinished(self.index, self.started.elapsed());
+        if tokio::time::Instant::now() >= self.deadline {
+            self.health.record_failure(self.index);
+        } else {
+            self.health.record_abandoned(self.index);
+        }
+    }
+}
+
+/// Upstreams that returned the same records, and the first such response.
+struct Agreement {
+    records: Vec<String>,
+    upstreams: Vec<usize>,
+    response: Vec<u8>,
+    ttl: Duration,
+}
+
+/// What upstreams must agree on under a consensus rule: the response code
+/// and the answer records, regardless of their TTLs, order and name case.
+fn consensus_records(response: &[u8]) -> Option<Vec<String>> {
+    let message = Message::from_bytes(response).ok()?;
+    let mut records: Vec<String> = message
+        .answers()
+        .iter()
+        .map(|record| {
+            format!(
+                "{} {} {}",
+                record.name().to_lowercase(),
+                record.record_type(),
+                record.data()
+            )
+        })
+        .collect();
+    records.sort();
+    records.insert(0, message.response_code().to_string());
+    Some(records)
+}
+
+/// SERVFAIL echoing the question. Clients that sent an OPT record also get
+/// the reason as an Extended DNS Error (RFC 8914).
+fn servfail(query: &Message, info_code: u16, text: &str) -> Option<Vec<u8>> {
+    let mut response = Message::new();
+    response.set_id(query.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(query.op_code());
+    response.set_recursion_desired(query.recursion_desired());
+    response.set_recursion_available(true);
+    response.set_response_code(ResponseCode::ServFail);
+    response.add_queries(query.queries().to_vec());
+
+    if query.extensions().is_some() {
+        let mut opt = Edns::new();
+        edns::add_extended_error(&mut opt, info_code, text);
+        response.set_edns(opt);
+    }
+
+    response.to_vec().ok()
+}
+
+/// How long a negative answer may be cached: the lower of the SOA record's
+/// TTL and its minimum field (RFC 2308 section 5), capped at `CACHE_TTL`.
+fn negative_ttl(message: &Message) -> Duration {
+    let ttl = message
+        .name_servers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            RData::SOA(soa) => Some(record.ttl().min(soa.minimum()) as u64),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(0);
+    Duration::from_secs(ttl.min(CACHE_TTL))
+}
+
+#[derive(Clone)]
 pub struct Server {
-    socket: Arc<UdpSocket>,
     cache: Arc<DnsCache>,
+    coalescer: Arc<Coalescer>,
     buf_size: usize,
+    edns_buffer_size: u16,
     dns_servers: Arc<Vec<ServerConfig>>,
+    balancer: Arc<Balancer>,
+    health: Arc<Health>,
+    router: Arc<Router>,
+    negative_answers: NegativePolicy,
+    priority_window: Duration,
 }
 
 impl Server {
-    pub fn new(socket: UdpSocket, buf_size: usize, dns_servers: Vec<ServerConfig>) -> Self {
+    pub fn new(buf_size: usize, config: &Config) -> Self {
         Self {
-            socket: Arc::new(socket),
             cache: Arc::new(DnsCache::new()),
+            coalescer: Arc::new(Coalescer::new()),
             buf_size,
-            dns_servers: Arc::new(dns_servers),
+            edns_buffer_size: config.edns_buffer_size,
+            dns_servers: Arc::new(config.servers.clone()),
+            balancer: Arc::new(Balancer::new(config)),
+            health: Arc::new(Health::new(&config.health, &config.servers)),
+            router: Arc::new(Router::new(config)),
+            negative_answers: config.negative_answers,
+            priority_window: Duration::from_millis(config.priority_window),
         }
     }
 
-    async fn handle_request(
-        socket: Arc<UdpSocket>,
-        cache: Arc<DnsCache>,
-        buf: Vec<u8>,
-        size: usize,
-        peer: SocketAddr,
-        dns_servers: Arc<Vec<ServerConfig>>,
-    ) -> std::io::Result<()> {
-        if let Ok(message) = Message::from_bytes(&buf[..size]) {
-            for query in message.queries() {
-                // Only handle A records for kubernetes-domains
-                if query
-                    .name()
-                    .to_ascii()
-                    .as_str()
-                    .ends_with(KUBERNETES_DOMAIN)
-                    || query
-                        .name()
-                        .to_ascii()
-                        .as_str()
-                        .ends_with(&format!("{}.", KUBERNETES_DOMAIN))
-                {
-                    // Non-A record query for kubernetes-domain, send empty response,
-                    if query.query_type().to_string() != "A" {
-                        let mut response = Message::new();
-                        response.set_id(message.id());
-                        response.set_message_type(MessageType::Response);
-                        response.set_response_code(ResponseCode::NoError);
-
-                        for q in message.queries() {
-                            response.add_query(q.clone());
-                        }
+    /// Resolve a single wire-format query and return the wire-format response.
+    /// Shared by every listener so all clients use the same cache.
+    pub(crate) async fn handle_request(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        let request = match Message::from_bytes(buf) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Error parsing DNS message: {}", e);
+                return None;
+            }
+        };
+
+        let response = self.resolve(buf).await?;
+        Some(edns::adapt_response(
+            &request,
+            response,
+            self.edns_buffer_size,
+        ))
+    }
+
+    async fn resolve(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        if let Ok(message) = Message::from_bytes(buf) {
+            if let Some(response) = self.answer_internal(&message) {
+                return Some(response);
+            }
+        }
+
+        if let Some(cached_response) = self.cache.get(buf).await {
+            return Some(cached_response);
+        }
+
+        // Clients asking the same question at once share one resolution
+        self.coalescer
+            .resolve(buf, || self.resolve_upstream(buf))
+            .await
+    }
 
-                        if let Ok(response_data) = response.to_vec() {
-                            socket.send_to(&response_data, peer).await?;
-                            return Ok(());
+    /// Answer record types an internal domain does not forward with its
+    /// synthetic response, so they never reach (or wait on) the upstreams.
+    fn answer_internal(&self, message: &Message) -> Option<Vec<u8>> {
+        let query = message.queries().first()?;
+        let (zone, internal) = self.router.internal(&query.name().to_ascii())?;
+        if internal.allow.contains(&query.query_type()) {
+            return None;
+        }
+
+        let mut response = Message::new();
+        response.set_id(message.id());
+        response.set_message_type(MessageType::Response);
+        response.set_op_code(message.op_code());
+        response.set_recursion_desired(message.recursion_desired());
+        response.set_recursion_available(true);
+        response.add_queries(message.queries().to_vec());
+
+        let response_code = match internal.response {
+            SyntheticResponse::NoData => ResponseCode::NoError,
+            SyntheticResponse::NxDomain => ResponseCode::NXDomain,
+            SyntheticResponse::Refused => ResponseCode::Refused,
+        };
+        response.set_response_code(response_code);
+
+        // Negative answers carry an SOA so clients know how long to cache them
+        if internal.response != SyntheticResponse::Refused {
+            let zone = Name::from_str(&format!("{}.", zone)).ok()?;
+            let soa = SOA::new(
+                Name::from_ascii("ns").ok()?.append_domain(&zone).ok()?,
+                Name::from_ascii("hostmaster")
+                    .ok()?
+                    .append_domain(&zone)
+                    .ok()?,
+                1,
+                3600,
+                600,
+                86400,
+                INTERNAL_SOA_TTL,
+            );
+            response.add_name_server(Record::from_rdata(zone, INTERNAL_SOA_TTL, RData::SOA(soa)));
+        }
+
+        response.to_vec().ok()
+    }
+
+    async fn resolve_upstream(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        match Message::from_bytes(buf) {
+            Ok(mut query) => {
+                edns::prepare_upstream_query(&mut query, self.edns_buffer_size);
+                match query.to_vec() {
+                    Ok(encoded_query) => {
+                        let name = query
+                            .queries()
+                            .first()
+                            .map(|q| q.name().to_ascii())
+                            .unwrap_or_default();
+                        let upstreams = self.router.route(&name);
+                        if let Some(rule) = self.router.consensus(&name) {
+                            return self
+                                .handle_consensus_queries(
+                                    encoded_query,
+                                    buf.to_vec(),
+                                    upstreams,
+                                    rule,
+                                )
+                                .await;
                         }
+                        return self
+                            .handle_dns_queries(encoded_query, buf.to_vec(), upstreams)
+                            .await;
                     }
+                    Err(e) => eprintln!("Error encoding query: {}", e),
                 }
             }
+            Err(e) => eprintln!("Error parsing DNS message: {}", e),
         }
+        None
+    }
 
-        if let Some(cached_response) = cache.get(&buf[..size]).await {
-            socket.send_to(&cached_response, peer).await?;
-            return Ok(());
-        }
+    /// Query one upstream in the background and report what it made of the
+    /// query on `tx`.
+    fn spawn_query(
+        &self,
+        index: usize,
+        query_data: Vec<u8>,
+        original_query: Vec<u8>,
+        tx: tokio::sync::mpsc::Sender<Reply>,
+        tasks: &mut UpstreamTasks,
+        deadline: tokio::time::Instant,
+    ) {
+        let dns_server = self.dns_servers[index].clone();
+        let attempt = Attempt {
+            index,
+            started: Instant::now(),
+            deadline,
+            balancer: Arc::clone(&self.balancer),
+            health: Arc::clone(&self.health),
+            finished: false,
+        };
+
+        tasks.0.push(tokio::spawn(async move {
+            let result =
+                match tokio::time::timeout_at(deadline, query_upstream(&dns_server, query_data))
+                    .await
+                {
+                    Ok(result) => result,
+                    Err(_) => Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "Upstream query timed out",
+                    )),
+                };
+            attempt.finish(result.is_ok());
 
-        match Message::from_bytes(&buf[..size]) {
-            Ok(query) => match query.to_vec() {
-                Ok(encoded_query) => {
-                    Self::handle_dns_queries(
-                        socket,
-                        cache,
-                        encoded_query,
-                        buf[..size].to_vec(),
-                        peer,
-                        dns_servers,
-                    )
-                    .await?;
+            let mut reply = Reply::Failed(index, Failure::NoAnswer);
+            match result {
+                Ok((server, Some(response))) => {
+                    if let Ok(response_message) = Message::from_bytes(&response) {
+                        let updated_response =
+                            DnsCache::update_dns_id(&original_query, response.to_vec());
+                        if let Some(updated_response) = updated_response {
+                            if is_negative(&response_message) {
+                                let ttl = negative_ttl(&response_message);
+                                reply = Reply::Negative(index, updated_response, ttl);
+                            } else if !response_message.answers().is_empty() {
+                                reply = Reply::Answer(index, updated_response);
+                            } else {
+                                println!("Empty response from {}", server);
+                            }
+                        }
+                    }
                 }
-                Err(e) => eprintln!("Error encoding query: {}", e),
-            },
-            Err(e) => eprintln!("Error parsing DNS message: {}", e),
+                Ok((_, None)) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    reply = Reply::Failed(index, Failure::Timeout);
+                }
+                Err(e) => reply = Reply::Failed(index, Failure::Network(e.to_string())),
+            }
+            let _ = tx.send(reply).await;
+        }));
+    }
+
+    /// Whether a negative answer may be forwarded, given how many upstreams
+    /// returned one, how many are still busy and how many could be asked.
+    fn negative_settled(&self, negatives: usize, in_flight: usize, candidates: usize) -> bool {
+        match self.negative_answers {
+            NegativePolicy::First => negatives > 0,
+            NegativePolicy::All => negatives > 0 && in_flight == 0,
+            NegativePolicy::Quorum => negatives * 2 > candidates,
         }
-        Ok(())
     }
 
+    /// Whether an upstream still busy with the query has a higher priority.
+    fn outranked(&self, priority: u32, pending: &[usize]) -> bool {
+        pending
+            .iter()
+            .any(|&index| self.dns_servers[index].priority > priority)
+    }
+
+    /// Query the given upstreams in batches planned by the balancer. The next batch
+    /// starts when the current one has failed or is slow to answer, and the
+    /// first answer from any upstream queried so far wins, unless an upstream
+    /// of higher priority answers within the priority window. Negative
+    /// answers are forwarded as the `negative_answers` policy allows.
     async fn handle_dns_queries(
-        socket: Arc<UdpSocket>,
-        cache: Arc<DnsCache>,
+        &self,
         encoded_query: Vec<u8>,
         original_query: Vec<u8>,
-        peer: SocketAddr,
-        dns_servers: Arc<Vec<ServerConfig>>,
-    ) -> std::io::Result<()> {
-        let timeout = tokio::time::Duration::from_secs(DNS_TIMEOUT);
-        let original_query_for_error = original_query.clone();
-        let (tx, mut rx) = tokio::sync::mpsc::channel(dns_servers.len());
+        upstreams: &[usize],
+    ) -> Option<Vec<u8>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.dns_servers.len().max(1));
+        let mut tasks = UpstreamTasks(Vec::new());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(DNS_TIMEOUT);
 
-        for dns_server in dns_servers.iter() {
-            let query_data = encoded_query.clone();
-            let original_query_cloned = original_query.clone();
-            let tx = tx.clone();
-            let dns_server = dns_server.clone();
+        let (batches, batch_delay) = self.balancer.plan(upstreams);
+        let mut batches = batches.iter().peekable();
+        let mut in_flight = 0;
+        let mut answer = None;
+        let mut negative = None;
+        let mut negatives = 0;
+        let mut pending = Vec::new();
+        let mut failures = Vec::new();
+        // The best answer so far, by upstream priority, and until when it
+        // waits for a better one.
+        let mut preferred: Option<(u32, Vec<u8>)> = None;
+        let mut window_end = None;
 
-            tokio::spawn(async move {
-                let result = if dns_server.use_tls {
-                    match tokio::time::timeout(
-                        timeout,
-                        query_dns_with_fallback(&dns_server.address, query_data),
-                    )
-                    .await
-                    {
-                        Ok(result) => result,
-                        Err(_) => Ok((dns_server.address.to_string(), None)),
-                    }
-                } else {
-                    match tokio::time::timeout(timeout, query_dns(&dns_server.address, query_data))
-                        .await
-                    {
-                        Ok(result) => result,
-                        Err(_) => Ok((dns_server.address.to_string(), None)),
+        while let Some(batch) = batches.next() {
+            for &index in batch {
+                // Upstreams with an open circuit are skipped
+                if !self.health.allow(index) {
+                    failures.push((index, Failure::CircuitOpen));
+                    continue;
+                }
+                self.spawn_query(
+                    index,
+                    encoded_query.clone(),
+                    original_query.clone(),
+                    tx.clone(),
+                    &mut tasks,
+                    deadline,
+                );
+                pending.push(index);
+                in_flight += 1;
+            }
+
+            let batch_deadline = if batches.peek().is_some() {
+                deadline.min(tokio::time::Instant::now() + batch_delay)
+            } else {
+                deadline
+            };
+
+            while in_flight > 0 {
+                if let Some((priority, _)) = &preferred {
+                    if !self.outranked(*priority, &pending) {
+                        break;
                     }
+                }
+                let wait_until = match window_end {
+                    Some(window_end) => deadline.min(window_end),
+                    None => batch_deadline,
                 };
 
-                if let Ok((server, Some(response))) = result {
-                    if let Ok(response_message) = Message::from_bytes(&response) {
-                        if !response_message.answers().is_empty() {
-                            if let Some(updated_response) =
-                                DnsCache::update_dns_id(&original_query_cloned, response.to_vec())
-                            {
-                                let _ = tx.send((server, updated_response)).await;
-                            }
-                        } else {
-                            println!("Empty response from {}", server);
+                match tokio::time::timeout_at(wait_until, rx.recv()).await {
+                    Ok(Some(Reply::Answer(index, response_data))) => {
+                        pending.retain(|&pending| pending != index);
+                        in_flight -= 1;
+                        let priority = self.dns_servers[index].priority;
+                        if preferred.as_ref().is_none_or(|(best, _)| priority > *best) {
+                            preferred = Some((priority, response_data));
+                        }
+                        window_end
+                            .get_or_insert(tokio::time::Instant::now() + self.priority_window);
+                    }
+                    Ok(Some(Reply::Negative(index, response_data, ttl))) => {
+                        pending.retain(|&pending| pending != index);
+                        in_flight -= 1;
+                        negatives += 1;
+                        negative.get_or_insert((response_data, ttl));
+                        if self.negative_settled(negatives, in_flight, upstreams.len()) {
+                            break;
                         }
                     }
+                    Ok(Some(Reply::Failed(index, failure))) => {
+                        pending.retain(|&pending| pending != index);
+                        failures.push((index, failure));
+                        in_flight -= 1;
+                    }
+                    Ok(None) | Err(_) => break,
                 }
-            });
+            }
+
+            if let Some((_, response_data)) = preferred.take() {
+                answer = Some((response_data, Duration::from_secs(CACHE_TTL)));
+            }
+            if answer.is_none() && self.negative_settled(negatives, in_flight, upstreams.len()) {
+                answer = negative.take();
+            }
+            if answer.is_some() || tokio::time::Instant::now() >= deadline {
+                break;
+            }
         }
 
-        match tokio::time::timeout(Duration::from_secs(DNS_TIMEOUT), rx.recv()).await {
-            Ok(Some((_, response_data))) => {
-                cache
-                    .set(
-                        original_query,
-                        response_data.clone(),
-                        Duration::from_secs(CACHE_TTL),
-                    )
+        // Upstreams that never replied do not hold back a negative answer forever.
+        if answer.is_none() && self.negative_answers != NegativePolicy::Quorum {
+            answer = negative;
+        }
+
+        match answer {
+            Some((response_data, ttl)) => {
+                self.cache
+                    .set(original_query, response_data.clone(), ttl)
                     .await;
 
-                socket.send_to(&response_data, peer).await?;
+                Some(response_data)
             }
 
             _ => {
-                let mut msg = Message::new();
-                msg.set_response_code(ResponseCode::NXDomain);
-                msg.set_message_type(MessageType::Response);
+                // Whatever is still pending missed the deadline.
+                failures.extend(pending.into_iter().map(|index| (index, Failure::Timeout)));
+                self.upstream_failure(&original_query, &failures)
+            }
+        }
+    }
 
-                if let Ok(query_message) = Message::from_bytes(&original_query_for_error) {
-                    let query_id = query_message.id();
-                    msg.set_id(query_id);
-                }
+    /// Query every upstream for a name under a consensus rule, and forward
+    /// the answer once `required` of them returned the same records. More
+    /// than `tolerance` differing answers, or too few agreeing ones, fail with
+    /// SERVFAIL and a log of what each upstream said.
+    async fn handle_consensus_queries(
+        &self,
+        encoded_query: Vec<u8>,
+        original_query: Vec<u8>,
+        upstreams: &[usize],
+        rule: &ConsensusRule,
+    ) -> Option<Vec<u8>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.dns_servers.len().max(1));
+        let mut tasks = UpstreamTasks(Vec::new());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(DNS_TIMEOUT);
+        let mut pending = Vec::new();
+        let mut failures = Vec::new();
 
-                if let Ok(response_data) = msg.to_vec() {
-                    socket.send_to(&response_data, peer).await?;
+        for &index in upstreams {
+            if !self.health.allow(index) {
+                failures.push((index, Failure::CircuitOpen));
+                continue;
+            }
+            self.spawn_query(
+                index,
+                encoded_query.clone(),
+                original_query.clone(),
+                tx.clone(),
+                &mut tasks,
+                deadline,
+            );
+            pending.push(index);
+        }
+
+        // Distinct answers, the most agreed on first.
+        let mut agreements: Vec<Agreement> = Vec::new();
+        while !pending.is_empty() {
+            let (index, response, ttl) = match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(Reply::Answer(index, response))) => {
+                    (index, response, Duration::from_secs(CACHE_TTL))
                 }
+                Ok(Some(Reply::Negative(index, response, ttl))) => (index, response, ttl),
+                Ok(Some(Reply::Failed(index, failure))) => {
+                    pending.retain(|&pending| pending != index);
+                    failures.push((index, failure));
+                    continue;
+                }
+                Ok(None) | Err(_) => break,
+            };
+            pending.retain(|&pending| pending != index);
+
+            let Some(records) = consensus_records(&response) else {
+                failures.push((index, Failure::NoAnswer));
+                continue;
+            };
+            match agreements.iter_mut().find(|a| a.records == records) {
+                Some(agreement) => agreement.upstreams.push(index),
+                None => agreements.push(Agreement {
+                    records,
+                    upstreams: vec![index],
+                    response,
+                    ttl,
+                }),
+            }
+            // A stable sort, so the earliest answer leads a tie
+            agreements.sort_by_key(|a| std::cmp::Reverse(a.upstreams.len()));
+
+            let dissent: usize = agreements[1..].iter().map(|a| a.upstreams.len()).sum();
+            if dissent > rule.tolerance {
+                break;
+            }
+            if agreements[0].upstreams.len() >= rule.required {
+                let agreement = agreements.swap_remove(0);
+                self.cache
+                    .set(original_query, agreement.response.clone(), agreement.ttl)
+                    .await;
+                return Some(agreement.response);
             }
         }
 
-        Ok(())
+        if agreements.is_empty() {
+            failures.extend(pending.into_iter().map(|index| (index, Failure::Timeout)));
+            return self.upstream_failure(&original_query, &failures);
+        }
+
+        let query = Message::from_bytes(&original_query).ok()?;
+        let mut diff = String::new();
+        for agreement in &agreements {
+            let names: Vec<String> = agreement
+                .upstreams
+                .iter()
+                .map(|&index| self.upstream_name(index))
+                .collect();
+            diff.push_str(&format!(
+                "\n  {}: {}",
+                names.join(", "),
+                agreement.records.join(", ")
+            ));
+        }
+        eprintln!(
+            "No consensus of {} upstreams for {:?}:{}",
+            rule.required,
+            query.queries(),
+            diff
+        );
+
+        let text = format!(
+            "{} upstreams gave {} different answers, {} must agree",
+            agreements.iter().map(|a| a.upstreams.len()).sum::<usize>(),
+            agreements.len(),
+            rule.required
+        );
+        servfail(&query, edns::EDE_OTHER, &text)
     }
 
-    pub async fn run(
-        self,
-        mut shutdown: tokio::sync::broadcast::Receiver<()>,
-    ) -> std::io::Result<()> {
-        let cache_clone = Arc::clone(&self.cache);
+    fn upstream_name(&self, index: usize) -> String {
+        let server = &self.dns_servers[index];
+        format!("{} ({})", server.address, server.description)
+    }
+
+    /// SERVFAIL for a query no upstream could answer, naming the upstreams
+    /// that failed and why.
+    fn upstream_failure(
+        &self,
+        original_query: &[u8],
+        failures: &[(usize, Failure)],
+    ) -> Option<Vec<u8>> {
+        let query = Message::from_bytes(original_query).ok()?;
+        let text = if failures.is_empty() {
+            "No upstreams for this name".to_string()
+        } else {
+            failures
+                .iter()
+                .map(|(index, failure)| format!("{}: {}", self.upstream_name(*index), failure))
+                .collect::<Vec<_>>()
+                .join("; ")
+        };
+        eprintln!("No upstream answered {:?}: {}", query.queries(), text);
 
-        let mut shutdown_cleanup = shutdown.resubscribe();
+        let network = failures
+            .iter()
+            .any(|(_, failure)| matches!(failure, Failure::Network(_)));
+        let info_code = if network {
+            edns::EDE_NETWORK_ERROR
+        } else {
+            edns::EDE_NO_REACHABLE_AUTHORITY
+        };
+        servfail(&query, info_code, &text)
+    }
+
+    /// Probe the upstreams in the background to open and close their circuits.
+    pub fn spawn_health_checks(&self, shutdown: tokio::sync::broadcast::Receiver<()>) {
+        self.health
+            .spawn_probes(Arc::clone(&self.dns_servers), shutdown);
+    }
+
+    /// Periodically evict expired entries from the shared cache.
+    pub fn spawn_cache_cleanup(&self, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+        let cache_clone = Arc::clone(&self.cache);
 
         tokio::spawn(async move {
             loop {
@@ -193,32 +695,46 @@ impl Server {
                         cache_clone.cleanup().await;
                     }
 
-                    _ = shutdown_cleanup.recv() => {
+                    _ = shutdown.recv() => {
                         println!("Cleanup task shutting down");
                         break;
                     }
                 }
             }
         });
+    }
+
+    pub async fn run(
+        self,
+        socket: UdpSocket,
+        mut shutdown: tokio::sync::broadcast::Receiver<()>,
+    ) -> std::io::Result<()> {
+        let socket = Arc::new(socket);
 
         loop {
             let mut buf = vec![0; self.buf_size];
 
             tokio::select! {
-                result = self.socket.recv_from(&mut buf) => {
+                result = socket.recv_from(&mut buf) => {
                     match result {
                         Ok((size, peer)) => {
-                            let socket_clone = Arc::clone(&self.socket);
-                            let cache_clone = Arc::clone(&self.cache);
-                            let dns_servers = Arc::clone(&self.dns_servers);
+                            let socket_clone = Arc::clone(&socket);
+                            let server = self.clone();
                             let mut shutdown_handler = shutdown.resubscribe();
 
                             tokio::spawn(async move {
                                 tokio::select! {
-                                    _ = Server::handle_request(socket_clone, cache_clone, buf, size, peer, dns_servers) => {}
-                                        _ = shutdown_handler.recv() => {
-                                            println!("Request handler shutting down");
+                                    response = server.handle_request(&buf[..size]) => {
+                                        if let Some(response_data) = response {
+                                            let response_data = edns::truncate_for_udp(&buf[..size], response_data);
+                                            if let Err(e) = socket_clone.send_to(&response_data, peer).await {
+                                                eprintln!("Error sending to {}: {}", peer, e);
+                                            }
                                         }
+                                    }
+                                    _ = shutdown_handler.recv() => {
+                                        println!("Request handler shutting down");
+                                    }
                                 }
                             });
                         }
@@ -235,4 +751,721 @@ impl Server {
         println!("Server shutdown complete");
         Ok(())
     }
+
+    pub async fn run_tcp(
+        self,
+        listener: TcpListener,
+        shutdown: tokio::sync::broadcast::Receiver<()>,
+    ) -> std::io::Result<()> {
+        self.run_stream_listener(listener, None, shutdown).await
+    }
+
+    /// DNS over TLS (RFC 7858) for clients, using the same framing as TCP.
+    pub async fn run_tls(
+        self,
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+        shutdown: tokio::sync::broadcast::Receiver<()>,
+    ) -> std::io::Result<()> {
+        self.run_stream_listener(listener, Some(acceptor), shutdown)
+            .await
+    }
+
+    async fn run_stream_listener(
+        self,
+        listener: TcpListener,
+        acceptor: Option<TlsAcceptor>,
+        mut shutdown: tokio::sync::broadcast::Receiver<()>,
+    ) -> std::io::Result<()> {
+        let protocol = if acceptor.is_some() { "TLS" } else { "TCP" };
+
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    match result {
+                        Ok((stream, peer)) => {
+                            let server = self.clone();
+                            let acceptor = acceptor.clone();
+                            let mut shutdown_handler = shutdown.resubscribe();
+
+                            tokio::spawn(async move {
+                                let connection = async {
+                                    match acceptor {
+                                        Some(acceptor) => {
+                                            let handshake = tokio::time::timeout(
+                                                Duration::from_secs(TCP_IDLE_TIMEOUT),
+                                                acceptor.accept(stream),
+                                            );
+                                            let tls_stream = handshake.await.map_err(|_| {
+                                                std::io::Error::new(
+                                                    std::io::ErrorKind::TimedOut,
+                                                    "TLS handshake timed out",
+                                                )
+                                            })??;
+                                            server.handle_stream(tls_stream, peer).await
+                                        }
+                                        None => server.handle_stream(stream, peer).await,
+                                    }
+                                };
+
+                                tokio::select! {
+                                    result = connection => {
+                                        if let Err(e) = result {
+                                            eprintln!("{} connection from {} failed: {}", protocol, peer, e);
+                                        }
+                                    }
+                                    _ = shutdown_handler.recv() => {
+                                        println!("{} connection handler shutting down", protocol);
+                                    }
+                                }
+                            });
+                        }
+                        Err(e) => eprintln!("Error accepting: {}", e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    println!("{} server loop shutting down", protocol);
+                    break;
+                }
+            }
+        }
+
+        println!("{} server shutdown complete", protocol);
+        Ok(())
+    }
+
+    /// Serve DNS messages framed with a 2-byte length prefix (RFC 7766).
+    /// Queries are resolved concurrently and answered in completion order,
+    /// so a client may pipeline several queries on one connection.
+    async fn handle_stream<S>(&self, stream: S, peer: SocketAddr) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(TCP_PIPELINE_LIMIT);
+        let pipeline = Arc::new(Semaphore::new(TCP_PIPELINE_LIMIT));
+        let idle_timeout = Duration::from_secs(TCP_IDLE_TIMEOUT);
+
+        let writer_task = tokio::spawn(async move {
+            while let Some(response) = rx.recv().await {
+                let length = (response.len() as u16).to_be_bytes();
+                writer.write_all(&length).await?;
+                writer.write_all(&response).await?;
+            }
+            writer.shutdown().await
+        });
+
+        'connection: loop {
+            let mut length_buf = [0u8; 2];
+            let mut filled = 0;
+
+            // `read` is cancel safe, so a timeout never loses part of the prefix.
+            while filled < length_buf.len() {
+                match tokio::time::timeout(idle_timeout, reader.read(&mut length_buf[filled..]))
+                    .await
+                {
+                    Ok(Ok(0)) => break 'connection,
+                    Ok(Ok(n)) => filled += n,
+                    Ok(Err(e)) => return Err(e),
+                    // Only count the connection as idle while no queries are outstanding.
+                    Err(_) if pipeline.available_permits() < TCP_PIPELINE_LIMIT => {}
+                    Err(_) => {
+                        println!("Closing idle TCP connection from {}", peer);
+                        break 'connection;
+                    }
+                }
+            }
+
+            let length = u16::from_be_bytes(length_buf) as usize;
+            let mut query = vec![0; length];
+            match tokio::time::timeout(idle_timeout, reader.read_exact(&mut query)).await {
+                Ok(result) => {
+                    result?;
+                }
+                Err(_) => {
+                    println!("Timed out reading query from {}", peer);
+                    break;
+                }
+            }
+
+            if length == 0 {
+                continue;
+            }
+
+            let permit = match Arc::clone(&pipeline).acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let server = self.clone();
+            let tx = tx.clone();
+
+            tokio::spawn(async move {
+                if let Some(response_data) = server.handle_request(&query).await {
+                    let _ = tx.send(response_data).await;
+                }
+                drop(permit);
+            });
+        }
+
+        // Let outstanding queries finish before the writer closes the stream.
+        drop(tx);
+        writer_task
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+
+    pub(crate) fn create_local_query(id: u16) -> Vec<u8> {
+        // Non-A queries for the default internal domain are answered without upstreams.
+        let mut message = Message::new();
+        message.set_id(id);
+        let name = Name::from_str("postgresql.invoice.svc.cluster.local.").unwrap();
+        message.add_query(Query::query(name, RecordType::AAAA));
+        message.to_vec().unwrap()
+    }
+
+    fn create_query(id: u16) -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(id);
+        let name = Name::from_str("www.example.com.").unwrap();
+        message.add_query(Query::query(name, RecordType::A));
+        message.to_vec().unwrap()
+    }
+
+    /// A UDP upstream on a random local port that answers every query with
+    /// `rcode`: one A record for NOERROR, the zone's SOA for NXDOMAIN and
+    /// nothing else otherwise. Returns the port and a count of the queries it
+    /// received.
+    async fn spawn_upstream(rcode: ResponseCode) -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+        use hickory_proto::rr::rdata::{A, SOA};
+        use hickory_proto::rr::Record;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok((size, peer)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut response = Message::from_bytes(&buf[..size]).unwrap();
+                response.set_message_type(MessageType::Response);
+                response.set_response_code(rcode);
+                let name = response.queries()[0].name().clone();
+                match rcode {
+                    ResponseCode::NoError => {
+                        response.add_answer(Record::from_rdata(
+                            name,
+                            60,
+                            RData::A(A::new(192, 0, 2, 1)),
+                        ));
+                    }
+                    ResponseCode::NXDomain => {
+                        let zone = name.base_name();
+                        let soa = SOA::new(zone.clone(), zone.clone(), 1, 3600, 600, 86400, 30);
+                        response.add_name_server(Record::from_rdata(zone, 900, RData::SOA(soa)));
+                    }
+                    _ => {}
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        (port, queries)
+    }
+
+    /// A UDP upstream that answers with 192.0.2.`host` after `delay`. Returns
+    /// the port and a count of the queries it received.
+    async fn spawn_delayed_upstream(
+        delay: Duration,
+        host: u8,
+    ) -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+        use hickory_proto::rr::rdata::A;
+        use hickory_proto::rr::Record;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let port = socket.local_addr().unwrap().port();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok((size, peer)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut response = Message::from_bytes(&buf[..size]).unwrap();
+                response.set_message_type(MessageType::Response);
+                let name = response.queries()[0].name().clone();
+                response.add_answer(Record::from_rdata(
+                    name,
+                    60,
+                    RData::A(A::new(192, 0, 2, host)),
+                ));
+                let socket = Arc::clone(&socket);
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+                });
+            }
+        });
+        (port, queries)
+    }
+
+    /// A port nothing listens on, so connections to it are refused right away.
+    pub(crate) fn closed_port() -> u16 {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        closed.local_addr().unwrap().port()
+    }
+
+    /// `settings` with a single TCP upstream, described as `closed`, on a
+    /// `closed_port`.
+    fn closed_upstream_config(settings: &str) -> Config {
+        toml::from_str(&format!(
+            "{}\n[[servers]]\naddress = \"127.0.0.1\"\nprotocol = \"tcp\"\nport = {}\ndescription = \"closed\"\n",
+            settings,
+            closed_port()
+        ))
+        .unwrap()
+    }
+
+    fn create_config(settings: &str, ports: &[u16]) -> Config {
+        let mut config = settings.to_string();
+        for port in ports {
+            config.push_str(&format!(
+                "\n[[servers]]\naddress = \"127.0.0.1\"\nport = {}\ndescription = \"test\"\n",
+                port
+            ));
+        }
+        toml::from_str(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_failed_upstream_opens_circuit() {
+        let config = closed_upstream_config("[health]\ninterval = 0\nfailure_threshold = 1\n");
+        let server = Server::new(1024, &config);
+        server.handle_request(&create_query(1)).await.unwrap();
+        assert!(!server.health.allow(0));
+    }
+
+    #[tokio::test]
+    async fn test_silent_upstream_opens_circuit() {
+        // Receives queries and never answers, like an upstream behind a dead VPN.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let config = create_config("[health]\ninterval = 0\nfailure_threshold = 1\n", &[port]);
+        let server = Server::new(1024, &config);
+
+        let response = server.handle_request(&create_query(1)).await.unwrap();
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+
+        let opened = async {
+            while server.health.allow(0) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), opened)
+            .await
+            .expect("timed out query not counted as a failure");
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_trial_reopens_circuit() {
+        let (fast, _) = spawn_upstream(ResponseCode::NoError).await;
+        let (slow, _) = spawn_delayed_upstream(Duration::from_secs(2), 2).await;
+        let config = create_config(
+            "[health]\ninterval = 0\nfailure_threshold = 1\nopen_duration = 0\n",
+            &[fast, slow],
+        );
+        let server = Server::new(1024, &config);
+        server.health.record_failure(1);
+
+        // The slow upstream's trial query loses to the fast one and is aborted.
+        let response = server.handle_request(&create_query(1)).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers().len(), 1);
+
+        let reopened = async {
+            while !server.health.allow(1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), reopened)
+            .await
+            .expect("circuit stuck half-open");
+    }
+
+    #[tokio::test]
+    async fn test_servfail_with_extended_errors() {
+        use hickory_proto::rr::rdata::opt::EdnsOption;
+
+        let server = Server::new(1024, &closed_upstream_config(""));
+
+        let mut query = Message::from_bytes(&create_query(11)).unwrap();
+        query.set_edns(Edns::new());
+        let response = server
+            .handle_request(&query.to_vec().unwrap())
+            .await
+            .unwrap();
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.id(), 11);
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+        assert_eq!(message.queries(), query.queries());
+        let options = message.extensions().as_ref().unwrap().options().as_ref();
+        let Some(EdnsOption::Unknown(15, data)) = options.first().map(|(_, option)| option) else {
+            panic!("No Extended DNS Error in {:?}", options);
+        };
+        assert_eq!(
+            u16::from_be_bytes([data[0], data[1]]),
+            edns::EDE_NETWORK_ERROR
+        );
+        assert!(String::from_utf8_lossy(&data[2..]).starts_with("127.0.0.1 (closed): "));
+
+        // Clients without EDNS get a plain SERVFAIL.
+        let response = server.handle_request(&create_query(12)).await.unwrap();
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+        assert!(message.extensions().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failover_strategy() {
+        use std::sync::atomic::Ordering;
+
+        let (refusing, refusing_queries) = spawn_upstream(ResponseCode::Refused).await;
+        let (answering, answering_queries) = spawn_upstream(ResponseCode::NoError).await;
+        let (unused, unused_queries) = spawn_upstream(ResponseCode::NoError).await;
+        let server = Server::new(
+            1024,
+            &create_config("strategy = \"failover\"", &[refusing, answering, unused]),
+        );
+
+        let response = server.handle_request(&create_query(42)).await.unwrap();
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.id(), 42);
+        assert_eq!(message.answers().len(), 1);
+        assert_eq!(refusing_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(answering_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(unused_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_negative_answer_policies() {
+        let (nxdomain, _) = spawn_upstream(ResponseCode::NXDomain).await;
+        let (refusing, _) = spawn_upstream(ResponseCode::Refused).await;
+        let (answering, _) = spawn_upstream(ResponseCode::NoError).await;
+
+        // Every upstream has replied, so the NXDOMAIN is forwarded with its SOA
+        // long before the query times out, and cached for the SOA minimum.
+        let server = Server::new(1024, &create_config("", &[nxdomain, refusing]));
+        let started = Instant::now();
+        let response = server.handle_request(&create_query(7)).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.id(), 7);
+        assert_eq!(message.response_code(), ResponseCode::NXDomain);
+        assert_eq!(message.name_servers().len(), 1);
+        assert_eq!(negative_ttl(&message), Duration::from_secs(30));
+        assert!(server.cache.get(&create_query(8)).await.is_some());
+
+        // An answer from any upstream beats the negative one.
+        let server = Server::new(1024, &create_config("", &[nxdomain, answering]));
+        let response = server.handle_request(&create_query(9)).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers().len(), 1);
+
+        // One negative out of three upstreams is no quorum.
+        let server = Server::new(
+            1024,
+            &create_config(
+                "negative_answers = \"quorum\"",
+                &[nxdomain, refusing, refusing],
+            ),
+        );
+        let response = server.handle_request(&create_query(10)).await.unwrap();
+        assert!(Message::from_bytes(&response)
+            .unwrap()
+            .name_servers()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_consensus() {
+        let (answering, _) = spawn_upstream(ResponseCode::NoError).await;
+        let (other, _) = spawn_upstream(ResponseCode::NoError).await;
+        let (nxdomain, _) = spawn_upstream(ResponseCode::NXDomain).await;
+        let rule = |tolerance: usize| {
+            format!(
+                "[[consensus]]\ndomains = [\"example.com\"]\nrequired = 2\ntolerance = {}",
+                tolerance
+            )
+        };
+
+        let server = Server::new(1024, &create_config(&rule(0), &[answering, other]));
+        let response = server.handle_request(&create_query(1)).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers().len(), 1);
+
+        // A single dissenting upstream is one too many without tolerance.
+        let server = Server::new(1024, &create_config(&rule(0), &[answering, nxdomain]));
+        let response = server.handle_request(&create_query(2)).await.unwrap();
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+        assert_eq!(message.queries().len(), 1);
+
+        let server = Server::new(
+            1024,
+            &create_config(&rule(1), &[answering, nxdomain, other]),
+        );
+        let response = server.handle_request(&create_query(3)).await.unwrap();
+        let message = Message::from_bytes(&response).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NoError);
+        assert_eq!(message.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_priority_window() {
+        use hickory_proto::rr::rdata::A;
+
+        let (public, _) = spawn_upstream(ResponseCode::NoError).await;
+        let (internal, _) = spawn_delayed_upstream(Duration::from_millis(100), 99).await;
+        let answer = |config: Config| async move {
+            let server = Server::new(1024, &config);
+            let response = server.handle_request(&create_query(1)).await.unwrap();
+            Message::from_bytes(&response).unwrap().answers()[0]
+                .data()
+                .clone()
+        };
+
+        // The internal view wins if it arrives within the window.
+        let mut config = create_config("priority_window = 1000", &[public, internal]);
+        config.servers[1].priority = 10;
+        assert_eq!(answer(config).await, RData::A(A::new(192, 0, 2, 99)));
+
+        // Once the window has passed, the public answer is used.
+        let mut config = create_config("priority_window = 10", &[public, internal]);
+        config.servers[1].priority = 10;
+        assert_eq!(answer(config).await, RData::A(A::new(192, 0, 2, 1)));
+
+        // Without priorities the first answer wins right away.
+        let config = create_config("priority_window = 1000", &[public, internal]);
+        assert_eq!(answer(config).await, RData::A(A::new(192, 0, 2, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_identical_queries_are_coalesced() {
+        use std::sync::atomic::Ordering;
+
+        let (port, queries) = spawn_delayed_upstream(Duration::from_millis(100), 1).await;
+        let server = Server::new(1024, &create_config("", &[port]));
+
+        let mut clients = tokio::task::JoinSet::new();
+        for id in 1..=5u16 {
+            let server = server.clone();
+            clients.spawn(async move {
+                let response = server.handle_request(&create_query(id)).await.unwrap();
+                (id, Message::from_bytes(&response).unwrap())
+            });
+        }
+        while let Some(result) = clients.join_next().await {
+            let (id, message) = result.unwrap();
+            assert_eq!(message.id(), id);
+            assert_eq!(message.answers().len(), 1);
+        }
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_losing_queries_are_cancelled() {
+        use tokio::io::AsyncReadExt;
+
+        let (fast, _) = spawn_upstream(ResponseCode::NoError).await;
+        // A TCP upstream that never answers and reports when the forwarder
+        // hangs up, which only happens early if the query is cancelled.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow = listener.local_addr().unwrap().port();
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 512];
+            while stream.read(&mut buf).await.unwrap_or(0) > 0 {}
+            let _ = closed_tx.send(());
+        });
+
+        let config = create_config(
+            &format!(
+                "[[servers]]\naddress = \"127.0.0.1\"\nprotocol = \"tcp\"\nport = {}\ndescription = \"slow\"\n",
+                slow
+            ),
+            &[fast],
+        );
+        let server = Server::new(1024, &config);
+
+        let cancelled = STATS.cancelled_queries();
+        let response = server.handle_request(&create_query(1)).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers().len(), 1);
+        assert!(STATS.cancelled_queries() > cancelled);
+        tokio::time::timeout(Duration::from_secs(1), closed_rx)
+            .await
+            .expect("losing query still running")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_queries_slow_down_upstream() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (fast, _) = spawn_upstream(ResponseCode::NoError).await;
+        let config = create_config(
+            "strategy = \"hedged\"\nhedge_delay = 20\n",
+            &[silent.local_addr().unwrap().port(), fast],
+        );
+        let server = Server::new(1024, &config);
+        // The silent upstream used to be the fastest.
+        server.balancer.record_latency(0, Duration::from_millis(1));
+        server.balancer.record_latency(1, Duration::from_millis(5));
+
+        server.handle_request(&create_query(1)).await.unwrap();
+        let reordered = async {
+            while server.balancer.order(&[0, 1]) != [1, 0] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), reordered)
+            .await
+            .expect("silent upstream still ordered first");
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn test_internal_domains() {
+        let (port, queries) = spawn_upstream(ResponseCode::NoError).await;
+        let config = create_config(
+            r#"
+            [[internal_domains]]
+            domains = ["svc.corp"]
+            allow = ["A", "srv"]
+            response = "nxdomain"
+
+            [[internal_domains]]
+            domains = ["consul"]
+            response = "refused"
+            "#,
+            &[port],
+        );
+        let server = Server::new(1024, &config);
+        let ask = |name: &str, record_type: RecordType| {
+            let mut message = Message::new();
+            message.set_id(3);
+            message.add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+            let server = server.clone();
+            async move {
+                let response = server
+                    .handle_request(&message.to_vec().unwrap())
+                    .await
+                    .unwrap();
+                Message::from_bytes(&response).unwrap()
+            }
+        };
+
+        let message = ask("db.svc.corp.", RecordType::AAAA).await;
+        assert_eq!(message.response_code(), ResponseCode::NXDomain);
+        assert_eq!(message.queries().len(), 1);
+        let soa = &message.name_servers()[0];
+        assert_eq!(soa.name(), &Name::from_str("svc.corp.").unwrap());
+        assert_eq!(negative_ttl(&message), Duration::from_secs(60));
+
+        let message = ask("web.consul.", RecordType::TXT).await;
+        assert_eq!(message.response_code(), ResponseCode::Refused);
+        assert!(message.name_servers().is_empty());
+
+        // Allowed types, and cluster.local once internal domains are configured,
+        // go to the upstreams.
+        assert_eq!(queries.load(std::sync::atomic::Ordering::Relaxed), 0);
+        let message = ask("_pg._tcp.db.svc.corp.", RecordType::SRV).await;
+        assert_eq!(message.answers().len(), 1);
+        let message = ask("postgresql.invoice.svc.cluster.local.", RecordType::AAAA).await;
+        assert_eq!(message.answers().len(), 1);
+        assert_eq!(queries.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_pipelined_queries() {
+        let server = Server::new(1024, &Config::default());
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let peer: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+        let handle = tokio::spawn(async move { server.handle_stream(stream, peer).await });
+
+        for id in [1u16, 2] {
+            let query = create_local_query(id);
+            client
+                .write_all(&(query.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            client.write_all(&query).await.unwrap();
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mut length_buf = [0u8; 2];
+            client.read_exact(&mut length_buf).await.unwrap();
+            let mut response = vec![0; u16::from_be_bytes(length_buf) as usize];
+            client.read_exact(&mut response).await.unwrap();
+            ids.push(Message::from_bytes(&response).unwrap().id());
+        }
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        client.shutdown().await.unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener() {
+        use crate::tls::tests::write_cert;
+        use crate::tls::{acceptor, ReloadingCertifiedKey};
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        let dir = std::env::temp_dir().join(format!("dns-lb-dot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, cert_path, key_path) = write_cert(&dir, "cert", "localhost");
+        let certified_key = ReloadingCertifiedKey::load(&cert_path, &key_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let server = Server::new(1024, &Config::default());
+        tokio::spawn(server.run_tls(listener, acceptor(certified_key, Vec::new()), shutdown_rx));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut tls_stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let query = create_local_query(53);
+        tls_stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        tls_stream.write_all(&query).await.unwrap();
+
+        let mut length_buf = [0u8; 2];
+        tls_stream.read_exact(&mut length_buf).await.unwrap();
+        let mut response = vec![0; u16::from_be_bytes(length_buf) as usize];
+        tls_stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().id(), 53);
+    }
 }
@@ -0,0 +1,56 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Process-wide counters for events that deserve attention in the logs.
+pub struct Stats {
+    downgrades: AtomicU64,
+    circuit_opens: AtomicU64,
+    cancelled_queries: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    downgrades: AtomicU64::new(0),
+    circuit_opens: AtomicU64::new(0),
+    cancelled_queries: AtomicU64::new(0),
+};
+
+impl Stats {
+    /// Count a query that was sent in cleartext because the encrypted transport failed.
+    pub fn record_downgrade(&self) -> u64 {
+        self.downgrades.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn downgrades(&self) -> u64 {
+        self.downgrades.load(Ordering::Relaxed)
+    }
+
+    /// Count an upstream being taken out of rotation by its circuit breaker.
+    pub fn record_circuit_open(&self) -> u64 {
+        self.circuit_opens.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn circuit_opens(&self) -> u64 {
+        self.circuit_opens.load(Ordering::Relaxed)
+    }
+
+    /// Count upstream queries aborted because nobody waits for their answer anymore.
+    pub fn record_cancelled_queries(&self, count: u64) -> u64 {
+        self.cancelled_queries.fetch_add(count, Ordering::Relaxed) + count
+    }
+
+    pub fn cancelled_queries(&self) -> u64 {
+        self.cancelled_queries.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "downgrades to cleartext: {}, circuits opened: {}, upstream queries cancelled: {}",
+            self.downgrades(),
+            self.circuit_opens(),
+            self.cancelled_queries()
+        )
+    }
+}
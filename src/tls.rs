@@ -0,0 +1,507 @@
+use crate::config::{ServerConfig as Upstream, CERT_RELOAD_INTERVAL};
+use ::aws_lc_rs::digest::{digest, SHA256};
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::{ResolvesClientCert, WebPkiServerVerifier};
+use tokio_rustls::rustls::crypto::aws_lc_rs::sign::any_supported_type;
+use tokio_rustls::rustls::crypto::{
+    aws_lc_rs, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::TlsAcceptor;
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = fs::read(path).map_err(|e| anyhow!("Reading {}: {}", path.display(), e))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Parsing {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = fs::read(path).map_err(|e| anyhow!("Reading {}: {}", path.display(), e))?;
+    PrivateKeyDer::from_pem_slice(&pem).map_err(|e| anyhow!("Parsing {}: {}", path.display(), e))
+}
+
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported key in {}: {}", key_path.display(), e))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+lazy_static! {
+    // Shared by every connector using the same files.
+    static ref CLIENT_CERTIFICATES: Mutex<HashMap<(PathBuf, PathBuf), Arc<ReloadingCertifiedKey>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// A certificate and key loaded from PEM files that is swapped in place when
+/// either file changes on disk, so rotated certificates apply without restart.
+pub struct ReloadingCertifiedKey {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCertifiedKey {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<Self>> {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+        Ok(Arc::new(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new((
+                Arc::new(certified_key),
+                modified(cert_path),
+                modified(key_path),
+            )),
+        }))
+    }
+
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        match self.current.read() {
+            Ok(current) => Arc::clone(&current.0),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner().0),
+        }
+    }
+
+    /// Reload if either file changed. A broken new file keeps the old key.
+    pub fn reload_if_changed(&self) -> bool {
+        let cert_modified = modified(&self.cert_path);
+        let key_modified = modified(&self.key_path);
+
+        {
+            let Ok(current) = self.current.read() else {
+                return false;
+            };
+            if current.1 == cert_modified && current.2 == key_modified {
+                return false;
+            }
+        }
+
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(certified_key) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = (Arc::new(certified_key), cert_modified, key_modified);
+                }
+                println!("Reloaded certificate {}", self.cert_path.display());
+                true
+            }
+            Err(e) => {
+                eprintln!(
+                    "Keeping previous certificate, reload of {} failed: {}",
+                    self.cert_path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Poll the files for changes until shutdown.
+    pub fn spawn_reload(self: &Arc<Self>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+        let this = Arc::clone(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(CERT_RELOAD_INTERVAL)) => {
+                        this.reload_if_changed();
+                    }
+
+                    _ = shutdown.recv() => break,
+                }
+            }
+        });
+    }
+}
+
+impl fmt::Debug for ReloadingCertifiedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingCertifiedKey")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertifiedKey {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+impl ResolvesClientCert for ReloadingCertifiedKey {
+    // Upstream connections are long-lived, so checking the files on each
+    // handshake is cheap and picks up rotated certificates without a timer.
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        self.reload_if_changed();
+        Some(self.current())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// TLS acceptor for inbound listeners, serving the reloadable certificate.
+pub fn acceptor(
+    certified_key: Arc<ReloadingCertifiedKey>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(certified_key);
+    config.alpn_protocols = alpn_protocols;
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Verifies upstream certificates against the configured roots and, when
+/// pins are given, requires the SubjectPublicKeyInfo to match one of them.
+/// Failures are logged with the upstream's name.
+#[derive(Debug)]
+struct UpstreamVerifier {
+    /// `address (description)` of the upstream, for logging.
+    upstream: String,
+    /// `None` when only the pins are trusted.
+    roots: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if let Some(roots) = &self.roots {
+            roots
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+                .inspect_err(|e| {
+                    eprintln!(
+                        "Certificate verification failed for upstream {} ({}): {}",
+                        self.upstream,
+                        server_name.to_str(),
+                        e
+                    )
+                })?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let cert = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|e| Error::General(format!("Unparsable certificate: {}", e)))?;
+        let spki_digest = digest(&SHA256, cert.subject_public_key_info().as_ref());
+        if self.pins.iter().any(|pin| pin == spki_digest.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            let message = format!(
+                "Certificate of upstream {} ({}) has SPKI pin {} which matches none of the configured pins",
+                self.upstream,
+                server_name.to_str(),
+                STANDARD.encode(spki_digest.as_ref())
+            );
+            eprintln!("{}", message);
+            Err(Error::General(message))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+fn parse_pin(pin: &str) -> Result<Vec<u8>> {
+    let pin = STANDARD
+        .decode(pin.trim())
+        .map_err(|e| anyhow!("Invalid SPKI pin {}: {}", pin, e))?;
+    if pin.len() != 32 {
+        return Err(anyhow!("SPKI pin must be a base64 SHA-256 digest"));
+    }
+    Ok(pin)
+}
+
+/// The client certificate for mutual TLS with an upstream, if it has one.
+fn client_certificate(upstream: &Upstream) -> Result<Option<Arc<ReloadingCertifiedKey>>> {
+    let (cert_path, key_path) = match (&upstream.client_cert, &upstream.client_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(anyhow!(
+                "Upstream {} needs both client_cert and client_key",
+                upstream.address
+            ))
+        }
+    };
+
+    let mut certificates = CLIENT_CERTIFICATES
+        .lock()
+        .map_err(|_| anyhow!("Client certificate lock poisoned"))?;
+    let key = (cert_path.clone(), key_path.clone());
+    if let Some(certified_key) = certificates.get(&key) {
+        return Ok(Some(Arc::clone(certified_key)));
+    }
+
+    let certified_key = ReloadingCertifiedKey::load(cert_path, key_path)?;
+    certificates.insert(key, Arc::clone(&certified_key));
+    Ok(Some(certified_key))
+}
+
+/// TLS settings for connections to an upstream, built from its trust anchors
+/// (`ca_file` and optionally the public roots) and SPKI pins.
+pub fn client_config(upstream: &Upstream, alpn_protocols: Vec<Vec<u8>>) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    if upstream.system_roots {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    if let Some(ca_file) = &upstream.ca_file {
+        for cert in load_certs(ca_file)? {
+            roots
+                .add(cert)
+                .map_err(|e| anyhow!("Invalid CA certificate in {}: {}", ca_file.display(), e))?;
+        }
+    }
+
+    let pins = upstream
+        .spki_pins
+        .iter()
+        .map(|pin| parse_pin(pin))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| anyhow!("Upstream {}: {}", upstream.address, e))?;
+    if roots.is_empty() && pins.is_empty() {
+        return Err(anyhow!(
+            "Upstream {} trusts no certificates: set ca_file, spki_pins or system_roots",
+            upstream.address
+        ));
+    }
+
+    let client_certificate = client_certificate(upstream)?;
+    let roots = if roots.is_empty() {
+        None
+    } else {
+        Some(WebPkiServerVerifier::builder(Arc::new(roots)).build()?)
+    };
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(UpstreamVerifier {
+            upstream: format!("{} ({})", upstream.address, upstream.description),
+            roots,
+            pins,
+            algorithms: aws_lc_rs::default_provider().signature_verification_algorithms,
+        }));
+    let mut config = match client_certificate {
+        Some(certified_key) => builder.with_client_cert_resolver(certified_key),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn_protocols;
+    Ok(config)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Writes a self-signed certificate for `name` to `<file>.pem` in `dir`
+    /// and its key to `<file>-key.pem`.
+    pub(crate) fn write_cert(
+        dir: &Path,
+        file: &str,
+        name: &str,
+    ) -> (rcgen::CertifiedKey, PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_path = dir.join(format!("{}.pem", file));
+        let key_path = dir.join(format!("{}-key.pem", file));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        (cert, cert_path, key_path)
+    }
+
+    #[test]
+    fn test_reload_on_file_change() {
+        let dir = std::env::temp_dir().join(format!("dns-lb-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let (_, cert_path, key_path) = write_cert(&dir, "cert", "first.example");
+        let certified_key = ReloadingCertifiedKey::load(&cert_path, &key_path).unwrap();
+        let first = certified_key.current().cert[0].clone();
+        assert!(!certified_key.reload_if_changed());
+
+        // Make sure the modification time moves even on coarse filesystems.
+        std::thread::sleep(Duration::from_millis(1100));
+        write_cert(&dir, "cert", "second.example");
+        assert!(certified_key.reload_if_changed());
+        assert_ne!(certified_key.current().cert[0], first);
+
+        // A broken file keeps serving the previous certificate.
+        std::thread::sleep(Duration::from_millis(1100));
+        fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(!certified_key.reload_if_changed());
+        assert_ne!(certified_key.current().cert[0], first);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn handshake(upstream: &Upstream, acceptor: &TlsAcceptor) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let _ = stream.write_all(b"ok").await;
+            }
+        });
+
+        let connector =
+            tokio_rustls::TlsConnector::from(Arc::new(client_config(upstream, Vec::new())?));
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        // With TLS 1.3 the server rejects a client certificate after our side
+        // of the handshake completes, so wait for it to say something.
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_ca_file_and_pins() {
+        let dir = std::env::temp_dir().join(format!("dns-lb-trust-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let (cert, cert_path, key_path) = write_cert(&dir, "ca", "localhost");
+        let acceptor = acceptor(
+            ReloadingCertifiedKey::load(&cert_path, &key_path).unwrap(),
+            Vec::new(),
+        );
+        let pin = STANDARD.encode(digest(&SHA256, &cert.key_pair.public_key_der()).as_ref());
+
+        let upstream = |extra: &str| -> Upstream {
+            toml::from_str(&format!(
+                "address = \"127.0.0.1\"\ndescription = \"test\"\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+
+        // The public roots alone do not trust a private CA.
+        assert!(handshake(&upstream(""), &acceptor).await.is_err());
+
+        let ca_file = format!("ca_file = {:?}", cert_path.display().to_string());
+        assert!(handshake(&upstream(&ca_file), &acceptor).await.is_ok());
+
+        let wrong_pin = format!(
+            "{}\nspki_pins = [{:?}]",
+            ca_file,
+            STANDARD.encode([0u8; 32])
+        );
+        assert!(handshake(&upstream(&wrong_pin), &acceptor).await.is_err());
+
+        // Pins alone are enough when no roots are trusted.
+        let pin_only = format!("system_roots = false\nspki_pins = [{:?}]", pin);
+        assert!(handshake(&upstream(&pin_only), &acceptor).await.is_ok());
+
+        assert!(client_config(&upstream("system_roots = false"), Vec::new()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upstream_client_certificate() {
+        use tokio_rustls::rustls::server::WebPkiClientVerifier;
+
+        let dir = std::env::temp_dir().join(format!("dns-lb-mtls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let (_, ca_path, key_path) = write_cert(&dir, "ca", "localhost");
+        let (client_cert, client_path, client_key_path) = write_cert(&dir, "client", "client");
+
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(client_cert.cert.der().clone()).unwrap();
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(
+                WebPkiClientVerifier::builder(Arc::new(client_roots))
+                    .build()
+                    .unwrap(),
+            )
+            .with_cert_resolver(ReloadingCertifiedKey::load(&ca_path, &key_path).unwrap());
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let upstream = |extra: &str| -> Upstream {
+            toml::from_str(&format!(
+                "address = \"127.0.0.1\"\ndescription = \"test\"\nca_file = {:?}\n{}",
+                ca_path.display().to_string(),
+                extra
+            ))
+            .unwrap()
+        };
+
+        assert!(handshake(&upstream(""), &acceptor).await.is_err());
+
+        let mutual = format!(
+            "client_cert = {:?}\nclient_key = {:?}",
+            client_path.display().to_string(),
+            client_key_path.display().to_string()
+        );
+        assert!(handshake(&upstream(&mutual), &acceptor).await.is_ok());
+
+        let half = format!("client_cert = {:?}", client_path.display().to_string());
+        assert!(client_config(&upstream(&half), Vec::new()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}